@@ -0,0 +1,91 @@
+//! Measures the per-request overhead of demogorgon before a request is sent
+//! to a backend.
+//!
+//! The `state` group compares handing each request its own clone of the
+//! `Config`, as the server used to do for every connection and request,
+//! with sharing the `Runtime` behind an `Arc`. The `handler` group measures
+//! requests that are answered without contacting a backend.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use demogorgon::config::Config;
+use demogorgon::runtime::Runtime;
+use demogorgon::service_handler;
+use hyper::{Body, Request};
+use std::sync::Arc;
+
+fn config() -> Config {
+    let mut config = format!(
+        r#"
+        address = "127.0.0.1:8000"
+
+        [auth]
+        algorithm = "ES256"
+        keyfile = "{}/benches/public_key.pem"
+        issuer = "demogorgon"
+        "#,
+        env!("CARGO_MANIFEST_DIR")
+    );
+    for i in 0..20 {
+        config.push_str(&format!(
+            r#"
+            [backends.backend{i}]
+            url = "https://backend{i}.example.com/api"
+            scope = "backend{i}:*"
+            headers = {{ "authorization" = "Bearer foobar", "x-backend" = "backend{i}" }}
+            "#,
+            i = i
+        ));
+    }
+    Config::parse(&config).unwrap()
+}
+
+fn request(path: &str) -> Request<Body> {
+    Request::get(path).body(Body::empty()).unwrap()
+}
+
+fn state(c: &mut Criterion) {
+    let config = config();
+    let runtime = Arc::new(Runtime::new(config.clone()).unwrap());
+
+    let mut group = c.benchmark_group("state");
+    group.bench_function("clone_config", |b| {
+        b.iter(|| {
+            let connection = black_box(config.clone());
+            black_box(connection.clone())
+        })
+    });
+    group.bench_function("clone_arc_runtime", |b| {
+        b.iter(|| {
+            let connection = black_box(runtime.clone());
+            black_box(connection.clone())
+        })
+    });
+    group.finish();
+}
+
+fn handler(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let runtime = Arc::new(Runtime::new(config()).unwrap());
+    let remote_addr = "127.0.0.1".parse().unwrap();
+
+    let mut group = c.benchmark_group("handler");
+    group.bench_function("not_found", |b| {
+        b.iter(|| {
+            let response = service_handler(request("/nope/"), remote_addr, runtime.clone());
+            black_box(rt.block_on(response).unwrap())
+        })
+    });
+    group.bench_function("missing_token", |b| {
+        b.iter(|| {
+            let response = service_handler(request("/backend7/"), remote_addr, runtime.clone());
+            black_box(rt.block_on(response).unwrap())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, state, handler);
+criterion_main!(benches);
@@ -0,0 +1,76 @@
+use crate::auth::network::NetworkAccess;
+use crate::errors::{ErrorRenderer, Problem};
+use crate::runtime::Runtime;
+use crate::upstream::health::HealthStatus;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// The admin listener, which reports the state of the proxy.
+///
+/// It requires no token, so it should be bound to a private address or
+/// restricted to trusted networks.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub address: SocketAddr,
+
+    #[serde(default)]
+    pub network: NetworkAccess,
+}
+
+#[derive(Serialize)]
+struct UpstreamState<'a> {
+    url: &'a str,
+    outstanding: usize,
+    #[serde(flatten)]
+    health: HealthStatus,
+}
+
+fn upstream_states(runtime: &Runtime) -> BTreeMap<&str, Vec<UpstreamState<'_>>> {
+    runtime
+        .config
+        .backends
+        .iter()
+        .map(|(name, backend)| {
+            let upstreams = backend
+                .upstreams
+                .iter()
+                .map(|upstream| UpstreamState {
+                    url: &upstream.url,
+                    outstanding: upstream.outstanding(),
+                    health: upstream.health().status(),
+                })
+                .collect();
+            (name.as_str(), upstreams)
+        })
+        .collect()
+}
+
+pub async fn admin_handler(
+    req: Request<Body>,
+    remote_addr: IpAddr,
+    runtime: Arc<Runtime>,
+) -> Result<Response<Body>, hyper::Error> {
+    let errors = ErrorRenderer::new(&runtime.config.errors, &req, Uuid::new_v4());
+    let admin = runtime.config.admin.as_ref();
+    if !admin.is_some_and(|admin| admin.network.permits(remote_addr)) {
+        log::warn!("Admin request from {} denied", remote_addr);
+        return Ok(errors.render(Problem::new(StatusCode::FORBIDDEN, "network-denied")));
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/upstreams") => {
+            let body = serde_json::to_string(&upstream_states(&runtime)).unwrap();
+            Ok(Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.into())
+                .unwrap())
+        }
+        _ => Ok(errors.render(Problem::not_found())),
+    }
+}
@@ -1,52 +1,178 @@
-use crate::config::{Backend, Config};
-use hyper::Request;
-use jsonwebtoken::errors::Error as JWTError;
+use crate::config::Backend;
+use crate::grpc;
+use crate::runtime::Runtime;
+use hyper::header::HeaderValue;
+use hyper::{Request, StatusCode};
+use ipnet::IpNet;
+use jsonwebtoken::errors::{Error as JWTError, ErrorKind as JWTErrorKind};
 use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::net::IpAddr;
 use uuid::Uuid;
 
+pub mod network;
+pub mod policy;
 pub mod scope;
 
+pub mod token;
+
 mod noauth;
-mod token;
+
+pub const REALM: &str = "demogorgon";
 
 pub enum AuthReason {
+    MissingCredentials(&'static str),
     BadRequest(&'static str),
     InvalidCredentials(JWTError),
     NotImplemented(&'static str),
-    InsufficientScope(String),
+    InsufficientScope(String, scope::ScopeEntry),
+    PolicyDenied(String),
+    NetworkDenied(String),
 }
 
-#[derive(Clone, Deserialize, Debug)]
-pub enum FrontendAuthType {
-    NoAuth,
-    Token,
+impl AuthReason {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthReason::MissingCredentials(_) | AuthReason::InvalidCredentials(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthReason::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AuthReason::NotImplemented(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AuthReason::InsufficientScope(..)
+            | AuthReason::PolicyDenied(_)
+            | AuthReason::NetworkDenied(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    /// Returns the RFC 6750 `WWW-Authenticate` challenge for the failure.
+    ///
+    /// Descriptions are generic so that token validation details are only
+    /// ever logged, never sent to the client.
+    pub fn challenge(&self) -> Option<HeaderValue> {
+        let params = match self {
+            AuthReason::MissingCredentials(_) => vec![],
+            AuthReason::BadRequest(reason) => vec![
+                ("error", "invalid_request".to_string()),
+                ("error_description", reason.to_string()),
+            ],
+            AuthReason::InvalidCredentials(err) => {
+                let description = match err.kind() {
+                    JWTErrorKind::ExpiredSignature => "The access token expired",
+                    _ => "The access token is invalid",
+                };
+                vec![
+                    ("error", "invalid_token".to_string()),
+                    ("error_description", description.to_string()),
+                ]
+            }
+            AuthReason::InsufficientScope(_, required) => vec![
+                ("error", "insufficient_scope".to_string()),
+                ("scope", required.to_string()),
+            ],
+            AuthReason::PolicyDenied(_) => vec![
+                ("error", "insufficient_scope".to_string()),
+                (
+                    "error_description",
+                    "The access token does not satisfy the policy".to_string(),
+                ),
+            ],
+            AuthReason::NotImplemented(_) | AuthReason::NetworkDenied(_) => return None,
+        };
+
+        let mut challenge = format!("Bearer realm=\"{}\"", REALM);
+        for (name, value) in params {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            challenge.push_str(&format!(", {}=\"{}\"", name, value));
+        }
+        HeaderValue::from_str(&challenge).ok()
+    }
 }
 
-impl FrontendAuthType {
-    pub fn default() -> Self {
-        FrontendAuthType::Token
+impl fmt::Display for AuthReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthReason::MissingCredentials(reason) => write!(f, "Missing credentials: {}", reason),
+            AuthReason::BadRequest(reason) => write!(f, "Bad Request: {}", reason),
+            AuthReason::InvalidCredentials(jwt_error) => write!(f, "Invalid token: {}", jwt_error),
+            AuthReason::NotImplemented(reason) => write!(f, "Not implemented: {}", reason),
+            AuthReason::InsufficientScope(reason, _) => write!(f, "Insufficient scope: {}", reason),
+            AuthReason::PolicyDenied(reason) => write!(f, "Policy denied: {}", reason),
+            AuthReason::NetworkDenied(reason) => write!(f, "Network denied: {}", reason),
+        }
     }
 }
 
+#[derive(Clone, Default, Deserialize, Debug)]
+pub enum FrontendAuthType {
+    NoAuth,
+    #[default]
+    Token,
+    TrustedNetwork {
+        networks: Vec<IpNet>,
+        scopes: Vec<scope::ScopeEntry>,
+    },
+}
+
 #[derive(Debug)]
 pub struct Authentication {
-    id: Option<Uuid>,
-    auth_type: FrontendAuthType,
-    scopes: Vec<scope::ScopeEntry>,
+    pub id: Option<Uuid>,
+    pub auth_type: FrontendAuthType,
+    pub scopes: Vec<scope::ScopeEntry>,
+    pub claims: Map<String, Value>,
 }
 
 impl Authentication {
-    pub fn authorize(&self, backend: &Backend) -> Result<scope::ScopeEntry, AuthReason> {
-        for token_scope in &self.scopes {
-            if token_scope > &backend.scope {
-                return Ok(token_scope.clone());
+    /// Returns the scopes granted on the backend.
+    ///
+    /// The first entry is the scope that satisfied the backend, followed by
+    /// any other allowed scopes under the backend's parent that are not
+    /// themselves denied.
+    pub fn authorize(&self, backend: &Backend) -> Result<Vec<scope::ScopeEntry>, AuthReason> {
+        let token_scope = match scope::ScopeEntry::find_allowed(
+            &self.scopes,
+            &backend.scope,
+            &backend.deny_scopes,
+        ) {
+            Some(token_scope) => token_scope,
+            None => {
+                return Err(AuthReason::InsufficientScope(
+                    format!(
+                        "{:?} is insufficient scope to reach {}",
+                        self.scopes, backend.scope
+                    ),
+                    backend.scope.clone(),
+                ))
+            }
+        };
+
+        let mut granted = vec![token_scope.clone()];
+        for s in &self.scopes {
+            let under_parent =
+                s.parent == scope::ScopeValue::Wildcard || s.parent == backend.scope.parent;
+            let denied = self.scopes.iter().any(|d| d.negated && d > s)
+                || backend.deny_scopes.iter().any(|d| d > s);
+            if !s.negated && under_parent && !denied && !granted.contains(s) {
+                granted.push(s.clone());
             }
         }
+        Ok(granted)
+    }
 
-        Err(AuthReason::InsufficientScope(format!(
-            "{:?} is insufficient scope to reach {}",
-            self.scopes, backend.scope
-        )))
+    /// Checks the scope that a backend in gRPC mode requires for `method`,
+    /// if any.
+    pub fn authorize_method(&self, backend: &Backend, method: &str) -> Result<(), AuthReason> {
+        let required = match backend.grpc.required_scope(method) {
+            Some(required) => required,
+            None => return Ok(()),
+        };
+        match scope::ScopeEntry::find_allowed(&self.scopes, required, &backend.deny_scopes) {
+            Some(_) => Ok(()),
+            None => Err(AuthReason::InsufficientScope(
+                format!("{:?} is insufficient scope to call {}", self.scopes, method),
+                required.clone(),
+            )),
+        }
     }
 }
 
@@ -54,20 +180,205 @@ pub trait Authenticator {
     fn authenticate<B>(&self, req: &Request<B>) -> Result<Authentication, AuthReason>;
 }
 
+/// Checks that a request may reach a backend. `upstream_path` is the path
+/// passed to the backend, which for a gRPC backend names the method called.
 pub fn request_is_authorized<B>(
     req: &Request<B>,
+    remote_addr: IpAddr,
     backend: &Backend,
-    config: &Config,
-) -> Result<scope::ScopeEntry, AuthReason> {
-    let authentication = match &backend.frontend_auth {
-        FrontendAuthType::Token => {
-            let authenticator = token::TokenAuthenticator::new(&config.auth);
-            authenticator.authenticate(req)?
+    upstream_path: &str,
+    runtime: &Runtime,
+) -> Result<Vec<scope::ScopeEntry>, AuthReason> {
+    for access in [&runtime.config.network, &backend.network].iter() {
+        if !access.permits(remote_addr) {
+            return Err(AuthReason::NetworkDenied(format!(
+                "{} is not permitted to reach this backend",
+                remote_addr
+            )));
         }
+    }
+
+    let authentication = match &backend.frontend_auth {
+        FrontendAuthType::Token => match &runtime.token_authenticator {
+            Some(authenticator) => authenticator.authenticate(req)?,
+            None => {
+                return Err(AuthReason::NotImplemented(
+                    "Token authentication is not set up",
+                ))
+            }
+        },
         FrontendAuthType::NoAuth => {
             let authenticator = noauth::NoAuthAuthenticator::new();
             authenticator.authenticate(req)?
         }
+        FrontendAuthType::TrustedNetwork { networks, scopes } => {
+            let authenticator = network::NetworkAuthenticator::new(networks, scopes, remote_addr);
+            authenticator.authenticate(req)?
+        }
     };
-    authentication.authorize(&backend)
+    let scopes = authentication.authorize(backend)?;
+
+    if backend.grpc.enabled {
+        authentication.authorize_method(backend, &grpc::method(upstream_path))?;
+    }
+
+    if let Some(policy) = &backend.policy {
+        if !policy.evaluate(&authentication, req, remote_addr) {
+            return Err(AuthReason::PolicyDenied(format!(
+                "{:?} ({:?}) does not satisfy the backend policy",
+                authentication.id, authentication.auth_type
+            )));
+        }
+    }
+    Ok(scopes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::scope::ScopeEntry;
+    use super::{request_is_authorized, AuthReason, Authentication, FrontendAuthType};
+    use crate::config::tests::config;
+    use crate::config::Config;
+    use crate::runtime::Runtime;
+    use hyper::{Request, StatusCode};
+    use jsonwebtoken::errors::{Error as JWTError, ErrorKind as JWTErrorKind};
+    use std::convert::TryFrom;
+
+    fn assert_challenge(reason: AuthReason, status: StatusCode, challenge: Option<&str>) {
+        assert_eq!(reason.status(), status);
+        assert_eq!(
+            reason.challenge().map(|c| c.to_str().unwrap().to_string()),
+            challenge.map(String::from)
+        );
+    }
+
+    #[test]
+    fn missing_credentials_challenge() {
+        assert_challenge(
+            AuthReason::MissingCredentials("Missing authorization header"),
+            StatusCode::UNAUTHORIZED,
+            Some("Bearer realm=\"demogorgon\""),
+        );
+    }
+
+    #[test]
+    fn invalid_token_challenge() {
+        assert_challenge(
+            AuthReason::InvalidCredentials(JWTError::from(JWTErrorKind::ExpiredSignature)),
+            StatusCode::UNAUTHORIZED,
+            Some("Bearer realm=\"demogorgon\", error=\"invalid_token\", error_description=\"The access token expired\""),
+        );
+        assert_challenge(
+            AuthReason::InvalidCredentials(JWTError::from(JWTErrorKind::InvalidSignature)),
+            StatusCode::UNAUTHORIZED,
+            Some("Bearer realm=\"demogorgon\", error=\"invalid_token\", error_description=\"The access token is invalid\""),
+        );
+    }
+
+    #[test]
+    fn insufficient_scope_challenge() {
+        assert_challenge(
+            AuthReason::InsufficientScope(
+                "nope".to_string(),
+                ScopeEntry::try_from("hass:*").unwrap(),
+            ),
+            StatusCode::FORBIDDEN,
+            Some("Bearer realm=\"demogorgon\", error=\"insufficient_scope\", scope=\"hass:*\""),
+        );
+    }
+
+    #[test]
+    fn invalid_request_challenge() {
+        assert_challenge(
+            AuthReason::BadRequest("Say \"please\""),
+            StatusCode::BAD_REQUEST,
+            Some("Bearer realm=\"demogorgon\", error=\"invalid_request\", error_description=\"Say \\\"please\\\"\""),
+        );
+    }
+
+    #[test]
+    fn no_challenge_for_server_and_network_errors() {
+        assert_challenge(
+            AuthReason::NotImplemented("nope"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            None,
+        );
+        assert_challenge(
+            AuthReason::NetworkDenied("nope".to_string()),
+            StatusCode::FORBIDDEN,
+            None,
+        );
+    }
+
+    fn grpc_config() -> Config {
+        config(
+            r#"
+            [backends.shop]
+            url = "http://shop.internal"
+            scope = "shop:*"
+            protocol = "Http2"
+            deny_scopes = ["shop:frozen"]
+            grpc = { enabled = true, methods = [
+                { method = "/shop.Orders/Cancel", scope = "shop:admin" },
+            ] }
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn authorize_method(scopes: &[&str], method: &str) -> Result<(), AuthReason> {
+        let authentication = Authentication {
+            id: None,
+            auth_type: FrontendAuthType::Token,
+            scopes: scopes
+                .iter()
+                .map(|s| ScopeEntry::try_from(*s).unwrap())
+                .collect(),
+            claims: Default::default(),
+        };
+        authentication.authorize_method(&grpc_config().backends["shop"], method)
+    }
+
+    #[test]
+    fn grpc_method_scopes() {
+        assert!(authorize_method(&["shop:*"], "/shop.Orders/List").is_ok());
+        assert!(authorize_method(&["shop:admin"], "/shop.Orders/Cancel").is_ok());
+        assert!(authorize_method(&["*:*"], "/shop.Orders/Cancel").is_ok());
+        let denied = authorize_method(&["shop:orders"], "/shop.Orders/Cancel").unwrap_err();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        assert!(authorize_method(&["shop:admin", "shop:frozen"], "/shop.Orders/Cancel").is_ok());
+    }
+
+    #[test]
+    fn grpc_method_is_taken_from_the_routed_path() {
+        let config = config(
+            r#"
+            [backends.orders]
+            url = "http://orders.internal"
+            scope = "orders:read"
+            protocol = "Http2"
+            frontend_auth = { TrustedNetwork = { networks = ["10.0.0.0/8"], scopes = ["orders:read"] } }
+            grpc = { enabled = true, methods = [
+                { method = "/shop.Orders/Cancel", scope = "orders:admin" },
+            ] }
+            "#,
+        )
+        .unwrap();
+        let runtime = Runtime::new(config).unwrap();
+        let authorize = |path: &str| {
+            let (_, backend, upstream_path) = runtime.route(None, path).unwrap();
+            let req = Request::post(path).body(()).unwrap();
+            request_is_authorized(
+                &req,
+                "10.0.0.1".parse().unwrap(),
+                backend,
+                &upstream_path,
+                &runtime,
+            )
+        };
+        assert!(authorize("/orders/shop.Orders/List").is_ok());
+        let denied = authorize("/orders/shop.Orders/Cancel").unwrap_err();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+    }
 }
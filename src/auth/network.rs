@@ -0,0 +1,131 @@
+use super::scope::ScopeEntry;
+use super::{AuthReason, Authentication, Authenticator, FrontendAuthType};
+use hyper::Request;
+use ipnet::IpNet;
+use serde::Deserialize;
+use serde_json::Map;
+use std::net::IpAddr;
+
+/// CIDR allow and deny lists for client addresses.
+///
+/// A deny always beats an allow. An empty allow list permits every address
+/// that is not denied.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkAccess {
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+impl NetworkAccess {
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        let addr = canonical_addr(addr);
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+    }
+}
+
+/// Maps IPv4-mapped IPv6 addresses, as seen on a dual-stack listener, back
+/// to IPv4 so that they match IPv4 networks.
+fn canonical_addr(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Grants the configured scopes to clients on trusted networks.
+pub struct NetworkAuthenticator<'a> {
+    networks: &'a [IpNet],
+    scopes: &'a [ScopeEntry],
+    remote_addr: IpAddr,
+}
+
+impl<'a> NetworkAuthenticator<'a> {
+    pub fn new(networks: &'a [IpNet], scopes: &'a [ScopeEntry], remote_addr: IpAddr) -> Self {
+        Self {
+            networks,
+            scopes,
+            remote_addr,
+        }
+    }
+}
+
+impl Authenticator for NetworkAuthenticator<'_> {
+    fn authenticate<B>(&self, _: &Request<B>) -> Result<Authentication, AuthReason> {
+        let addr = canonical_addr(self.remote_addr);
+        if !self.networks.iter().any(|net| net.contains(&addr)) {
+            return Err(AuthReason::NetworkDenied(format!(
+                "{} is not on a trusted network",
+                self.remote_addr
+            )));
+        }
+        Ok(Authentication {
+            id: None,
+            auth_type: FrontendAuthType::TrustedNetwork {
+                networks: self.networks.to_vec(),
+                scopes: self.scopes.to_vec(),
+            },
+            scopes: self.scopes.to_vec(),
+            claims: Map::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::NetworkAccess;
+
+    fn network_access(allow: &[&str], deny: &[&str]) -> NetworkAccess {
+        NetworkAccess {
+            allow: allow.iter().map(|n| n.parse().unwrap()).collect(),
+            deny: deny.iter().map(|n| n.parse().unwrap()).collect(),
+        }
+    }
+
+    fn permits(access: &NetworkAccess, addr: &str) -> bool {
+        access.permits(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn empty_permits_all() {
+        let access = network_access(&[], &[]);
+        assert!(permits(&access, "192.0.2.1"));
+        assert!(permits(&access, "2001:db8::1"));
+    }
+
+    #[test]
+    fn allow_list() {
+        let access = network_access(&["10.0.0.0/8", "2001:db8::/32"], &[]);
+        assert!(permits(&access, "10.1.2.3"));
+        assert!(permits(&access, "2001:db8::1"));
+        assert!(!permits(&access, "192.0.2.1"));
+        assert!(!permits(&access, "2001:db9::1"));
+    }
+
+    #[test]
+    fn deny_beats_allow() {
+        let access = network_access(&["10.0.0.0/8"], &["10.66.0.0/16"]);
+        assert!(permits(&access, "10.1.2.3"));
+        assert!(!permits(&access, "10.66.0.1"));
+
+        let access = network_access(&[], &["192.0.2.0/24"]);
+        assert!(!permits(&access, "192.0.2.1"));
+        assert!(permits(&access, "198.51.100.1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let access = network_access(&["10.0.0.0/8"], &[]);
+        assert!(permits(&access, "::ffff:10.1.2.3"));
+        assert!(!permits(&access, "::ffff:192.0.2.1"));
+    }
+}
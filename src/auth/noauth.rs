@@ -1,6 +1,7 @@
 use super::scope::ScopeEntry;
 use super::{AuthReason, Authentication, Authenticator, FrontendAuthType};
 use hyper::Request;
+use serde_json::Map;
 use std::convert::TryFrom;
 
 pub struct NoAuthAuthenticator {}
@@ -17,6 +18,7 @@ impl Authenticator for NoAuthAuthenticator {
             id: None,
             auth_type: FrontendAuthType::NoAuth,
             scopes: vec![ScopeEntry::try_from("*:*").unwrap()],
+            claims: Map::new(),
         })
     }
 }
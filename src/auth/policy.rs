@@ -0,0 +1,444 @@
+use super::Authentication;
+use hyper::Request;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+
+/// A boolean expression over the claims of a token and the request.
+///
+/// Policies are written as strings in the config, for example:
+///
+/// ```text
+/// claim.tenant == header.x-tenant && claim.email_verified == true
+/// ```
+///
+/// Operands are literals (`"string"`, numbers, `true`, `false`, `null`) or
+/// references: `claim.<name>[.<name>...]`, `header.<name>`, `method`, `path`
+/// and `ip`. Operators are `==`, `!=`, `in` (list or substring membership),
+/// `!`, `&&`, `||` and parentheses. A policy cannot do anything other than
+/// compare values, so evaluating one has no side effects.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Policy {
+    Literal(Value),
+    Claim(Vec<String>),
+    Header(String),
+    Method,
+    Path,
+    Ip,
+    Not(Box<Policy>),
+    And(Box<Policy>, Box<Policy>),
+    Or(Box<Policy>, Box<Policy>),
+    Eq(Box<Policy>, Box<Policy>),
+    Ne(Box<Policy>, Box<Policy>),
+    In(Box<Policy>, Box<Policy>),
+}
+
+impl Policy {
+    /// Returns true if the request satisfies the policy.
+    pub fn evaluate<B>(
+        &self,
+        authentication: &Authentication,
+        req: &Request<B>,
+        remote_addr: IpAddr,
+    ) -> bool {
+        truthy(&self.value(authentication, req, remote_addr))
+    }
+
+    fn value<B>(
+        &self,
+        authentication: &Authentication,
+        req: &Request<B>,
+        remote_addr: IpAddr,
+    ) -> Value {
+        let eval = |p: &Policy| p.value(authentication, req, remote_addr);
+        match self {
+            Policy::Literal(v) => v.clone(),
+            Policy::Claim(path) => {
+                let mut parts = path.iter();
+                let first = parts.next().and_then(|p| authentication.claims.get(p));
+                parts
+                    .try_fold(first, |v, p| Some(v?.get(p)))
+                    .flatten()
+                    .cloned()
+                    .unwrap_or(Value::Null)
+            }
+            Policy::Header(name) => match req.headers().get(name.as_str()) {
+                Some(v) => match v.to_str() {
+                    Ok(s) => Value::String(s.to_string()),
+                    Err(_) => Value::Null,
+                },
+                None => Value::Null,
+            },
+            Policy::Method => Value::String(req.method().to_string()),
+            Policy::Path => Value::String(req.uri().path().to_string()),
+            Policy::Ip => Value::String(remote_addr.to_string()),
+            Policy::Not(a) => Value::Bool(!truthy(&eval(a))),
+            Policy::And(a, b) => Value::Bool(truthy(&eval(a)) && truthy(&eval(b))),
+            Policy::Or(a, b) => Value::Bool(truthy(&eval(a)) || truthy(&eval(b))),
+            Policy::Eq(a, b) => Value::Bool(equal(&eval(a), &eval(b))),
+            Policy::Ne(a, b) => Value::Bool(!equal(&eval(a), &eval(b))),
+            Policy::In(a, b) => Value::Bool(match (eval(a), eval(b)) {
+                (needle, Value::Array(list)) => list.iter().any(|v| equal(&needle, v)),
+                (Value::String(needle), Value::String(haystack)) => haystack.contains(&needle),
+                _ => false,
+            }),
+        }
+    }
+}
+
+/// Compares two values, treating `3` and `3.0` as equal.
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        _ => true,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Str(String),
+    Num(f64),
+    Ident(String),
+    Eq,
+    Ne,
+    Not,
+    And,
+    Or,
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(e) => s.push(e),
+                            None => return Err("Unterminated string".to_string()),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err("Unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            '=' | '!' | '&' | '|' => {
+                chars.next();
+                let next = chars.peek().copied();
+                let token = match (c, next) {
+                    ('=', Some('=')) => Token::Eq,
+                    ('!', Some('=')) => Token::Ne,
+                    ('&', Some('&')) => Token::And,
+                    ('|', Some('|')) => Token::Or,
+                    ('!', _) => {
+                        tokens.push(Token::Not);
+                        continue;
+                    }
+                    _ => return Err(format!("Unexpected character '{}'", c)),
+                };
+                chars.next();
+                tokens.push(token);
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut s = String::new();
+                while let Some(&d) = chars.peek() {
+                    if d.is_ascii_digit() || d == '.' || d == '-' {
+                        s.push(d);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let n = s.parse().map_err(|_| format!("Invalid number '{}'", s))?;
+                tokens.push(Token::Num(n));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut s = String::new();
+                while let Some(&d) = chars.peek() {
+                    if d.is_ascii_alphanumeric() || d == '_' || d == '-' || d == '.' {
+                        s.push(d);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ident(s));
+            }
+            _ => return Err(format!("Unexpected character '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Policy, String> {
+        let mut lhs = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            lhs = Policy::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Policy, String> {
+        let mut lhs = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            lhs = Policy::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Policy, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Policy::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Policy, String> {
+        let lhs = self.parse_operand()?;
+        let op: fn(Box<Policy>, Box<Policy>) -> Policy = match self.peek() {
+            Some(Token::Eq) => Policy::Eq,
+            Some(Token::Ne) => Policy::Ne,
+            Some(Token::Ident(i)) if i == "in" => Policy::In,
+            _ => return Ok(lhs),
+        };
+        self.next();
+        let rhs = self.parse_operand()?;
+        Ok(op(Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn parse_operand(&mut self) -> Result<Policy, String> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let inner = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(inner),
+                    _ => Err("Expected ')'".to_string()),
+                }
+            }
+            Some(Token::Str(s)) => Ok(Policy::Literal(Value::String(s))),
+            Some(Token::Num(n)) => Ok(Policy::Literal(Value::from(n))),
+            Some(Token::Ident(i)) => parse_reference(&i),
+            Some(t) => Err(format!("Unexpected token {:?}", t)),
+            None => Err("Unexpected end of policy".to_string()),
+        }
+    }
+}
+
+fn parse_reference(ident: &str) -> Result<Policy, String> {
+    match ident {
+        "true" => return Ok(Policy::Literal(Value::Bool(true))),
+        "false" => return Ok(Policy::Literal(Value::Bool(false))),
+        "null" => return Ok(Policy::Literal(Value::Null)),
+        "method" => return Ok(Policy::Method),
+        "path" => return Ok(Policy::Path),
+        "ip" => return Ok(Policy::Ip),
+        _ => {}
+    }
+
+    match ident.split_once('.') {
+        Some(("claim", rest)) if !rest.is_empty() && !rest.split('.').any(str::is_empty) => {
+            Ok(Policy::Claim(rest.split('.').map(String::from).collect()))
+        }
+        Some(("header", name)) if !name.is_empty() && !name.contains('.') => {
+            Ok(Policy::Header(name.to_lowercase()))
+        }
+        _ => Err(format!("Unknown reference '{}'", ident)),
+    }
+}
+
+impl TryFrom<&str> for Policy {
+    type Error = String;
+
+    fn try_from(val: &str) -> Result<Self, Self::Error> {
+        let mut parser = Parser {
+            tokens: tokenize(val)?,
+            pos: 0,
+        };
+        let policy = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(policy),
+            Some(t) => Err(format!("Unexpected token {:?}", t)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Policy {
+    fn deserialize<D>(deserializer: D) -> Result<Policy, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PolicyVisitor)
+    }
+}
+
+struct PolicyVisitor;
+
+impl<'de> Visitor<'de> for PolicyVisitor {
+    type Value = Policy;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a valid policy expression")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Policy::try_from(value).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Policy;
+    use crate::auth::{Authentication, FrontendAuthType};
+    use hyper::Request;
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    fn evaluate(policy: &str, claims: serde_json::Value, req: Request<()>) -> bool {
+        let policy = Policy::try_from(policy).unwrap();
+        let authentication = Authentication {
+            id: None,
+            auth_type: FrontendAuthType::Token,
+            scopes: vec![],
+            claims: serde_json::from_value(claims).unwrap(),
+        };
+        policy.evaluate(&authentication, &req, "10.0.0.1".parse().unwrap())
+    }
+
+    fn request() -> Request<()> {
+        Request::builder()
+            .method("POST")
+            .uri("http://example.com/hass/api")
+            .header("X-Tenant", "acme")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn claim_compares_with_header() {
+        let policy = "claim.tenant == header.x-tenant";
+        assert!(evaluate(policy, json!({"tenant": "acme"}), request()));
+        assert!(!evaluate(policy, json!({"tenant": "other"}), request()));
+        assert!(!evaluate(policy, json!({}), request()));
+    }
+
+    #[test]
+    fn boolean_claims() {
+        let policy = "claim.email_verified == true";
+        assert!(evaluate(policy, json!({"email_verified": true}), request()));
+        assert!(!evaluate(
+            policy,
+            json!({"email_verified": false}),
+            request()
+        ));
+        assert!(!evaluate(
+            policy,
+            json!({"email_verified": "true"}),
+            request()
+        ));
+        assert!(evaluate(
+            "claim.email_verified",
+            json!({"email_verified": true}),
+            request()
+        ));
+        assert!(!evaluate("claim.missing", json!({}), request()));
+    }
+
+    #[test]
+    fn nested_claims_and_membership() {
+        let claims = json!({"org": {"groups": ["admin", "ops"]}});
+        assert!(evaluate(
+            "\"admin\" in claim.org.groups",
+            claims.clone(),
+            request()
+        ));
+        assert!(!evaluate("\"dev\" in claim.org.groups", claims, request()));
+        assert!(evaluate("\"/api\" in path", json!({}), request()));
+    }
+
+    #[test]
+    fn request_attributes() {
+        assert!(evaluate("method == \"POST\"", json!({}), request()));
+        assert!(evaluate("path == \"/hass/api\"", json!({}), request()));
+        assert!(evaluate("ip == \"10.0.0.1\"", json!({}), request()));
+        assert!(evaluate("ip != \"10.0.0.2\"", json!({}), request()));
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert!(evaluate("true || false && false", json!({}), request()));
+        assert!(!evaluate("(true || false) && false", json!({}), request()));
+        assert!(evaluate(
+            "!false && !(method == \"GET\")",
+            json!({}),
+            request()
+        ));
+        assert!(evaluate(
+            "claim.level == 3",
+            json!({"level": 3.0}),
+            request()
+        ));
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        assert!(Policy::try_from("").is_err());
+        assert!(Policy::try_from("claim.").is_err());
+        assert!(Policy::try_from("foo == 1").is_err());
+        assert!(Policy::try_from("method = \"GET\"").is_err());
+        assert!(Policy::try_from("(true").is_err());
+        assert!(Policy::try_from("true false").is_err());
+        assert!(Policy::try_from("\"unterminated").is_err());
+    }
+}
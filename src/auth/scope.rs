@@ -54,10 +54,43 @@ impl<'de> Visitor<'de> for ScopeValueVisitor {
     }
 }
 
+/// A `parent:child` scope, optionally prefixed with `!` to deny it.
+///
+/// Ordering ignores negation: `outer > inner` means that `outer` covers
+/// `inner`, whether `outer` is an allow or a deny entry.
 #[derive(PartialEq, Clone, Debug)]
 pub struct ScopeEntry {
     pub parent: ScopeValue,
     pub child: ScopeValue,
+    pub negated: bool,
+}
+
+impl ScopeEntry {
+    /// Returns the allowed scope covering `required`, if any.
+    ///
+    /// Deny entries take scopes away before the request is judged,
+    /// regardless of the order of the entries:
+    ///
+    /// 1. If any deny entry in `scopes` covers `required`, access is denied.
+    /// 2. Allow entries in `scopes` covered by an entry in `denied` are
+    ///    ignored.
+    /// 3. Otherwise, the first remaining allow entry covering `required` is
+    ///    returned.
+    pub fn find_allowed<'a>(
+        scopes: &'a [ScopeEntry],
+        required: &ScopeEntry,
+        denied: &[ScopeEntry],
+    ) -> Option<&'a ScopeEntry> {
+        let (denies, allows): (Vec<_>, Vec<_>) = scopes.iter().partition(|s| s.negated);
+
+        if denies.iter().any(|d| *d > required) {
+            return None;
+        }
+        allows
+            .into_iter()
+            .filter(|a| !denied.iter().any(|d| d > *a))
+            .find(|a| *a > required)
+    }
 }
 
 impl PartialOrd for ScopeEntry {
@@ -74,7 +107,8 @@ impl PartialOrd for ScopeEntry {
 
 impl fmt::Display for ScopeEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.parent, self.child)
+        let prefix = if self.negated { "!" } else { "" };
+        write!(f, "{}{}:{}", prefix, self.parent, self.child)
     }
 }
 
@@ -82,13 +116,21 @@ impl TryFrom<&str> for ScopeEntry {
     type Error = &'static str;
 
     fn try_from(val: &str) -> Result<Self, Self::Error> {
+        let (negated, val) = match val.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, val),
+        };
         let parts: Vec<&str> = val.split(':').collect();
 
         match parts.len() {
             2 => {
                 let parent = ScopeValue::from(parts[0]);
                 let child = ScopeValue::from(parts[1]);
-                Ok(ScopeEntry { parent, child })
+                Ok(ScopeEntry {
+                    parent,
+                    child,
+                    negated,
+                })
             }
             _ => Err("ScopeEntry must be single level"),
         }
@@ -128,6 +170,7 @@ impl<'de> Visitor<'de> for ScopeEntryVisitor {
 mod tests {
 
     use super::ScopeEntry;
+    use std::cmp::Ordering;
     use std::convert::TryFrom;
 
     fn assert_scope(outer: &str, inner: &str) {
@@ -139,7 +182,7 @@ mod tests {
     fn assert_not_scope(outer: &str, inner: &str) {
         let outer = ScopeEntry::try_from(outer).unwrap();
         let inner = ScopeEntry::try_from(inner).unwrap();
-        assert!(!(outer > inner));
+        assert_ne!(outer.partial_cmp(&inner), Some(Ordering::Greater));
     }
     #[test]
     fn wildcard_outer_allows_all() {
@@ -179,4 +222,74 @@ mod tests {
         assert_not_scope("bees:bees", "bar:foo");
         assert_not_scope("bees:bees", "foo:foo");
     }
+
+    fn allowed(scopes: &[&str], required: &str, denied: &[&str]) -> Option<String> {
+        let parse = |s: &[&str]| -> Vec<ScopeEntry> {
+            s.iter()
+                .map(|s| ScopeEntry::try_from(*s).unwrap())
+                .collect()
+        };
+        let scopes = parse(scopes);
+        let required = ScopeEntry::try_from(required).unwrap();
+        ScopeEntry::find_allowed(&scopes, &required, &parse(denied)).map(|s| s.to_string())
+    }
+
+    #[test]
+    fn negated_entries_parse() {
+        let entry = ScopeEntry::try_from("!hass:admin").unwrap();
+        assert!(entry.negated);
+        assert_eq!(entry.to_string(), "!hass:admin");
+        assert!(!ScopeEntry::try_from("hass:admin").unwrap().negated);
+        assert!(ScopeEntry::try_from("!hass").is_err());
+    }
+
+    #[test]
+    fn negation_is_ignored_for_ordering() {
+        assert_scope("!*:*", "hass:admin");
+        assert_scope("!hass:*", "hass:admin");
+        assert_not_scope("!hass:admin", "hass:*");
+    }
+
+    #[test]
+    fn allow_without_deny() {
+        assert_eq!(allowed(&["*:*"], "hass:admin", &[]), Some("*:*".into()));
+        assert_eq!(allowed(&["cats:*"], "hass:admin", &[]), None);
+        assert_eq!(allowed(&["!cats:*"], "cats:cat", &[]), None);
+    }
+
+    #[test]
+    fn token_deny_beats_allow() {
+        assert_eq!(allowed(&["*:*", "!hass:admin"], "hass:admin", &[]), None);
+        assert_eq!(allowed(&["!hass:admin", "*:*"], "hass:admin", &[]), None);
+        assert_eq!(allowed(&["*:*", "!hass:*"], "hass:admin", &[]), None);
+        assert_eq!(allowed(&["hass:admin", "!*:*"], "hass:admin", &[]), None);
+    }
+
+    #[test]
+    fn token_deny_only_affects_covered_scopes() {
+        let scopes = &["*:*", "!hass:admin"];
+        assert_eq!(allowed(scopes, "hass:lights", &[]), Some("*:*".into()));
+        assert_eq!(allowed(scopes, "cats:cat", &[]), Some("*:*".into()));
+        assert_eq!(allowed(scopes, "hass:*", &[]), Some("*:*".into()));
+    }
+
+    #[test]
+    fn backend_deny_removes_covered_scopes() {
+        let denied = &["!hass:guest"];
+        assert_eq!(allowed(&["hass:guest"], "hass:guest", denied), None);
+        assert_eq!(
+            allowed(&["hass:guest", "hass:*"], "hass:*", denied),
+            Some("hass:*".into())
+        );
+        assert_eq!(
+            allowed(&["hass:*"], "hass:*", denied),
+            Some("hass:*".into())
+        );
+        assert_eq!(
+            allowed(&["hass:guest"], "hass:guest", &["hass:guest"]),
+            None
+        );
+        assert_eq!(allowed(&["*:*"], "hass:*", denied), Some("*:*".into()));
+        assert_eq!(allowed(&["*:*"], "hass:*", &["!*:*"]), None);
+    }
 }
@@ -6,45 +6,63 @@ use hyper::header::{HeaderValue, AUTHORIZATION};
 use hyper::Request;
 use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::error::Error;
 use std::fs;
 use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
 struct Claims {
     sub: Uuid,
-    exp: usize,
     scopes: Vec<ScopeEntry>,
 }
 
 pub struct TokenAuthenticator {
-    config: TokenAuthConfig,
+    validation: Validation,
+    key: Option<DecodingKey<'static>>,
 }
 
 impl TokenAuthenticator {
-    pub fn new(config: &TokenAuthConfig) -> Self {
-        Self {
-            config: config.clone(),
-        }
+    pub fn new(config: &TokenAuthConfig) -> Result<Self, Box<dyn Error>> {
+        let key = match config.algorithm {
+            Algorithm::ES256 | Algorithm::ES384 => Some(load_ec_decoding_key(&config.keyfile)?),
+            _ => None,
+        };
+        Ok(Self {
+            validation: TokenAuthenticator::get_jwt_validation(config),
+            key,
+        })
     }
 
     fn get_authorization_header<B>(req: &Request<B>) -> Result<&HeaderValue, AuthReason> {
         match req.headers().get(AUTHORIZATION) {
             Some(header) => Ok(header),
-            None => Err(AuthReason::BadRequest("Missing authorization header")),
+            None => Err(AuthReason::MissingCredentials(
+                "Missing authorization header",
+            )),
         }
     }
 
     fn extract_token_from_header(header: &HeaderValue) -> Result<&str, AuthReason> {
-        let header = header.to_str().expect("Unable to parse header as str");
+        let header = match header.to_str() {
+            Ok(h) => h,
+            Err(_) => {
+                return Err(AuthReason::BadRequest(
+                    "Unable to parse authorization header",
+                ))
+            }
+        };
         if !header.starts_with("Bearer ") {
-            return Err(AuthReason::BadRequest("Authorization must be Bearer"));
+            return Err(AuthReason::MissingCredentials(
+                "Authorization must be Bearer",
+            ));
         }
         Ok(header.trim_start_matches("Bearer "))
     }
 
-    fn get_jwt_validation(&self) -> Validation {
-        let mut validation = Validation::new(self.config.algorithm);
-        validation.iss = Some(String::from(&self.config.issuer));
+    fn get_jwt_validation(config: &TokenAuthConfig) -> Validation {
+        let mut validation = Validation::new(config.algorithm);
+        validation.iss = Some(String::from(&config.issuer));
         validation
     }
 }
@@ -54,34 +72,37 @@ impl Authenticator for TokenAuthenticator {
         let header = TokenAuthenticator::get_authorization_header(req)?;
         let token = TokenAuthenticator::extract_token_from_header(header)?;
 
-        let (validation, key) = match self.config.algorithm {
-            Algorithm::ES256 | Algorithm::ES384 => (
-                self.get_jwt_validation(),
-                load_ec_decoding_key(&self.config.keyfile),
-            ),
-            _ => {
+        let key = match &self.key {
+            Some(key) => key,
+            None => {
                 return Err(AuthReason::NotImplemented(
                     "Unable to use non ES key, not implemented",
                 ))
             }
         };
 
-        let token_data = match decode::<Claims>(&token, &key, &validation) {
+        let token_data = match decode::<Map<String, Value>>(token, key, &self.validation) {
             Ok(c) => c,
             Err(err) => return Err(AuthReason::InvalidCredentials(err)),
         };
 
+        let claims = Value::Object(token_data.claims.clone());
+        let claims: Claims = match serde_json::from_value(claims) {
+            Ok(c) => c,
+            Err(err) => return Err(AuthReason::InvalidCredentials(err.into())),
+        };
+
         Ok(Authentication {
-            id: Some(token_data.claims.sub),
+            id: Some(claims.sub),
             auth_type: FrontendAuthType::Token,
-            scopes: token_data.claims.scopes,
+            scopes: claims.scopes,
+            claims: token_data.claims,
         })
     }
 }
 
-fn load_ec_decoding_key(filename: &str) -> DecodingKey<'static> {
-    let secret = fs::read(filename).unwrap_or_else(|_| panic!("Unable to read file public key"));
-    DecodingKey::from_ec_pem(&secret)
-        .map(DecodingKey::into_static)
-        .unwrap()
+fn load_ec_decoding_key(filename: &str) -> Result<DecodingKey<'static>, Box<dyn Error>> {
+    let secret = fs::read(filename)
+        .map_err(|e| format!("Unable to read public key file {}: {}", filename, e))?;
+    Ok(DecodingKey::from_ec_pem(&secret).map(DecodingKey::into_static)?)
 }
@@ -1,5 +1,19 @@
-use crate::auth::{scope::ScopeEntry, FrontendAuthType};
+use crate::admin::AdminConfig;
+use crate::auth::{network::NetworkAccess, policy::Policy, scope::ScopeEntry, FrontendAuthType};
+use crate::errors::{pages::ErrorPages, ErrorConfig};
+use crate::grpc::GrpcConfig;
+use crate::index::IndexConfig;
+use crate::paths::PathConfig;
+use crate::redirect::ForeignRedirects;
+use crate::routing::{HostPattern, RouteConfig, RouteTable};
+use crate::tls::server::ListenerTlsConfig;
 use crate::tls::ClientCertAuth;
+use crate::upgrade::UpgradeConfig;
+use crate::upstream::balance::{self, BalanceStrategy, Upstream, UpstreamConfig};
+use crate::upstream::breaker::{CircuitBreaker, CircuitBreakerConfig};
+use crate::upstream::health::{HealthCheckConfig, OutlierConfig};
+use crate::upstream::retry::{RetryBudget, RetryConfig};
+use crate::upstream::{TimeoutConfig, UpstreamProtocol};
 use hyper::client::connect::HttpConnector;
 use hyper::Client;
 use jsonwebtoken::Algorithm;
@@ -8,66 +22,235 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::net::SocketAddr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+pub type HttpsClient = Client<hyper_rustls::HttpsConnector<HttpConnector>, hyper::Body>;
 
 #[derive(Clone, Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Backend {
     cert_auth: Option<ClientCertAuth>,
     pub headers: Option<HashMap<String, String>>,
-    pub url: String,
+    /// Shorthand for a single upstream, which is moved into `upstreams` when
+    /// the config is loaded.
+    url: Option<String>,
     pub scope: ScopeEntry,
 
+    /// Hosts the backend is served on, such as `app.example.com` or
+    /// `*.example.com`, which matches one label below `example.com` as TLS
+    /// certificates do. When empty, the backend is served on every host.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    /// Shorthand for a single route serving everything below a path, which
+    /// is moved into `routes` when the config is loaded. Without either, the
+    /// backend is served under `/<name>`, or at the root when it has `hosts`.
+    prefix: Option<String>,
+
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+
+    /// The upstreams as configured, from which `upstreams` is built when the
+    /// config is loaded.
+    #[serde(default, rename = "upstreams")]
+    upstream_configs: Vec<UpstreamConfig>,
+
+    #[serde(skip)]
+    pub upstreams: Vec<Upstream>,
+
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+
+    /// Scopes that cannot be used to reach the backend, with or without a
+    /// leading `!`.
+    #[serde(default)]
+    pub deny_scopes: Vec<ScopeEntry>,
+
     #[serde(default = "default_scope_header")]
     pub scope_header: String,
 
     #[serde(default)]
     pub scope_header_pass_full: bool,
 
-    #[serde(default = "FrontendAuthType::default")]
+    #[serde(default)]
+    pub scope_header_mode: ScopeHeaderMode,
+
+    #[serde(default)]
     pub frontend_auth: FrontendAuthType,
+
+    #[serde(default)]
+    pub foreign_redirects: ForeignRedirects,
+
+    #[serde(default)]
+    pub cookies: CookieRewrite,
+
+    #[serde(default)]
+    pub upgrade: UpgradeConfig,
+
+    #[serde(default)]
+    pub grpc: GrpcConfig,
+
+    pub policy: Option<Policy>,
+
+    #[serde(default)]
+    pub network: NetworkAccess,
+
+    #[serde(default)]
+    pub error_pages: ErrorPages,
+
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+
+    #[serde(default)]
+    pub pool: PoolConfig,
+
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    pub health_check: Option<HealthCheckConfig>,
+
+    pub outlier_detection: Option<OutlierConfig>,
+
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    #[serde(skip)]
+    next_upstream: Arc<AtomicUsize>,
+
+    #[serde(skip)]
+    retry_budget: RetryBudget,
+
+    #[serde(skip)]
+    breaker: Option<CircuitBreaker>,
+}
+
+/// Connection pool tuning for the client of a backend.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Seconds after which idle connections are closed.
+    pub idle_timeout: Option<u64>,
+    /// Maximum number of idle connections kept per host.
+    pub max_idle_per_host: Option<usize>,
+}
+
+/// HTTP/2 support on the listener.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Http2Config {
+    /// Accept HTTP/2 without TLS from clients with prior knowledge (h2c).
+    #[serde(default)]
+    pub h2c: bool,
+}
+
+/// How the granted scopes are passed to the backend in the scope header.
+#[derive(Clone, Default, Deserialize, Debug)]
+pub enum ScopeHeaderMode {
+    /// Only the scope that satisfied the backend.
+    #[default]
+    First,
+    /// All granted scopes under the backend's parent, comma-separated.
+    List,
+    /// All granted scopes under the backend's parent, as a JSON array.
+    Json,
+}
+
+/// Which attributes of `Set-Cookie` headers from the backend are rewritten,
+/// so that cookies are scoped to the backend's public prefix.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CookieRewrite {
+    /// Replace `Domain` with the public host the client used.
+    #[serde(default)]
+    pub domain: bool,
+    /// Map `Path` to the public path of the route serving it, or to the
+    /// public root of the backend if no route does.
+    #[serde(default)]
+    pub path: bool,
 }
 
 fn default_scope_header() -> String {
     "X-Demogorgon-Scope".to_string()
 }
 
+fn load_root_store() -> Result<rustls::RootCertStore, Box<dyn Error>> {
+    match rustls_native_certs::load_native_certs() {
+        Ok(store) => Ok(store),
+        Err((Some(store), err)) => {
+            log::warn!("Could not load all certificates: {:?}", err);
+            Ok(store)
+        }
+        Err((None, err)) => Err(format!("Cannot access native cert store: {}", err).into()),
+    }
+}
+
 impl Backend {
-    fn get_client_config() -> rustls::ClientConfig {
-        let mut tls = rustls::ClientConfig::new();
-        tls.root_store = match rustls_native_certs::load_native_certs() {
-            Ok(store) => store,
-            Err((Some(store), err)) => {
-                log::warn!("Could not load all certificates: {:?}", err);
-                store
-            }
-            Err((None, err)) => Err(err).expect("cannot access native cert store"),
-        };
-        tls
-    }
-
-    pub fn get_client(&self) -> Client<hyper_rustls::HttpsConnector<HttpConnector>, hyper::Body> {
-        let https = match &self.cert_auth {
-            Some(ca) => {
-                log::debug!("Creating HTTPS client with Cert Auth");
-                let mut http = hyper::client::HttpConnector::new();
-                http.enforce_http(false);
-                let mut tls = Backend::get_client_config();
-
-                let (cert_chain, privkey) =
-                    ca.get_client_cert().expect("Unable to load client cert.");
-                tls.set_single_client_cert(cert_chain, privkey)
-                    .expect("TLS Error when loading client cert.");
-                hyper_rustls::HttpsConnector::from((http, tls))
-            }
-            None => {
-                log::debug!("Creating HTTPS client");
-                let mut http = hyper::client::HttpConnector::new();
-                http.enforce_http(false);
-                let tls = Backend::get_client_config();
-                hyper_rustls::HttpsConnector::from((http, tls))
-            }
+    /// Picks the upstream for an attempt at a request, skipping the upstreams
+    /// already `tried` for it where possible.
+    pub fn pick_upstream(&self, tried: &[usize]) -> usize {
+        balance::pick(&self.balance, &self.upstreams, &self.next_upstream, tried)
+    }
+
+    /// Returns the retry budget, which is shared by every request to the
+    /// backend.
+    pub fn retry_budget(&self) -> &RetryBudget {
+        &self.retry_budget
+    }
+
+    pub fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_ref()
+    }
+
+    /// Maps the rest of a path after the URL of an upstream to the public
+    /// path serving it, using the first prefix route that serves it.
+    pub fn public_path(&self, upstream_rest: &str) -> Option<String> {
+        self.routes
+            .iter()
+            .find_map(|r| r.public_path(upstream_rest))
+    }
+
+    /// Returns the prefix of the first prefix route, or `/` if there is
+    /// none or it is the root.
+    pub fn public_root(&self) -> String {
+        match self.routes.iter().find_map(|r| r.public_prefix()) {
+            Some("") | None => "/".to_string(),
+            Some(prefix) => prefix.to_string(),
+        }
+    }
+
+    /// Returns where the backend can be reached from, as a path, or as a
+    /// scheme-relative URL for backends served on hosts. Backends served only
+    /// on wildcard hosts or by regex routes have no such URL.
+    pub fn public_url(&self) -> Option<String> {
+        let path = match self.routes.iter().find_map(|r| r.public_prefix())? {
+            "" => "/",
+            prefix => prefix,
         };
-        Client::builder().build(https)
+        self.public_location(path)
+    }
+
+    /// Returns where a public path of the backend is reached from: the path
+    /// itself, or for backends served on hosts, a scheme-relative URL on the
+    /// first exact host.
+    pub fn public_location(&self, path: &str) -> Option<String> {
+        if self.hosts.is_empty() {
+            return Some(path.to_string());
+        }
+        let host = self.hosts.iter().find(|host| !host.starts_with('*'))?;
+        Some(format!("//{}{}", host.to_ascii_lowercase(), path))
+    }
+
+    fn validate_route(&self) -> Result<(), String> {
+        if let Some(host) = self.hosts.iter().find(|h| HostPattern::parse(h).is_none()) {
+            return Err(format!("host {} is not a valid host name", host));
+        }
+        for route in &self.routes {
+            route.validate()?;
+        }
+        Ok(())
     }
 }
 
@@ -84,15 +267,226 @@ pub struct TokenAuthConfig {
 pub struct Config {
     pub address: SocketAddr,
     pub auth: TokenAuthConfig,
+
+    #[serde(default)]
+    pub network: NetworkAccess,
+
+    #[serde(default)]
+    pub errors: ErrorConfig,
+
+    pub tls: Option<ListenerTlsConfig>,
+
+    #[serde(default)]
+    pub http2: Http2Config,
+
+    #[serde(default)]
+    pub paths: PathConfig,
+
+    pub admin: Option<AdminConfig>,
+
+    pub index: Option<IndexConfig>,
+
+    /// Backend serving requests that no route matches, with their whole
+    /// path.
+    pub default_backend: Option<String>,
+
     pub backends: HashMap<String, Backend>,
+
+    #[serde(skip)]
+    pub routes: RouteTable,
 }
 
 impl Config {
     pub fn load(filename: &str) -> Result<Config, Box<dyn Error>> {
         log::debug!("Loading config file from {}", filename);
         let contents = fs::read_to_string(filename)?;
-        let config: Config = toml::from_str(&contents)?;
+        Config::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, Box<dyn Error>> {
+        let mut config: Config = toml::from_str(contents)?;
         log::debug!("Loaded configuration: {:?}", config);
+        config.collect_shorthand()?;
+        config.validate()?;
+        config.build_routes()?;
+        config.build_clients()?;
         Ok(config)
     }
+
+    /// Moves the `url` and `cert_auth` shorthand of each backend into its
+    /// list of upstreams, and its `prefix` into its list of routes.
+    fn collect_shorthand(&mut self) -> Result<(), Box<dyn Error>> {
+        for (name, backend) in self.backends.iter_mut() {
+            match backend.url.take() {
+                Some(_) if !backend.upstream_configs.is_empty() => {
+                    return Err(
+                        format!("Backend {}: set either url or upstreams, not both", name).into(),
+                    );
+                }
+                Some(url) => {
+                    let upstream = UpstreamConfig::new(url, backend.cert_auth.take());
+                    backend.upstream_configs.push(upstream);
+                }
+                None if backend.upstream_configs.is_empty() => {
+                    return Err(format!("Backend {}: url or upstreams is required", name).into());
+                }
+                None if backend.cert_auth.is_some() => {
+                    return Err(format!(
+                        "Backend {}: set cert_auth on each upstream instead",
+                        name
+                    )
+                    .into());
+                }
+                None => {}
+            }
+            match backend.prefix.take() {
+                Some(_) if !backend.routes.is_empty() => {
+                    return Err(
+                        format!("Backend {}: set either prefix or routes, not both", name).into(),
+                    );
+                }
+                Some(prefix) => backend.routes.push(RouteConfig::with_prefix(&prefix)),
+                None if !backend.routes.is_empty() => {}
+                None if backend.hosts.is_empty() => {
+                    let prefix = format!("/{}", name);
+                    backend.routes.push(RouteConfig::with_prefix(&prefix));
+                }
+                None => backend.routes.push(RouteConfig::with_prefix("/")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the route table, failing if two routes conflict or if a
+    /// route serves the path of the index.
+    fn build_routes(&mut self) -> Result<(), Box<dyn Error>> {
+        self.routes = RouteTable::new(&self.backends, self.default_backend.as_deref())?;
+        if let Some(index) = &self.index {
+            if let Some((name, route)) = self.routes.serving(&index.path) {
+                return Err(format!(
+                    "index path {} is also served by backend {} under {}",
+                    index.path, name, route
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn build_clients(&mut self) -> Result<(), Box<dyn Error>> {
+        let root_store = load_root_store()?;
+        for (name, backend) in self.backends.iter_mut() {
+            for config in std::mem::take(&mut backend.upstream_configs) {
+                let upstream = Upstream::new(
+                    config,
+                    &root_store,
+                    &backend.pool,
+                    &backend.timeouts,
+                    backend.protocol,
+                )
+                .map_err(|e| format!("Backend {}: unable to create client: {}", name, e))?;
+                backend.upstreams.push(upstream);
+            }
+            backend.retry_budget = RetryBudget::new(&backend.retry);
+            backend.breaker = backend
+                .circuit_breaker
+                .as_ref()
+                .map(|config| CircuitBreaker::new(name, config));
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(tls) = &self.tls {
+            tls.validate()?;
+        }
+        if let Some(index) = &self.index {
+            index.validate()?;
+            if self.default_backend.is_some() {
+                return Err("set either index or default_backend, not both".into());
+            }
+        }
+        if let Some(name) = &self.default_backend {
+            if !self.backends.contains_key(name) {
+                return Err(format!("default_backend {} is not a backend", name).into());
+            }
+        }
+        for (name, backend) in &self.backends {
+            if backend.scope.negated {
+                return Err(format!("Backend {}: scope cannot be negative", name).into());
+            }
+            backend
+                .timeouts
+                .validate()
+                .map_err(|e| format!("Backend {}: {}", name, e))?;
+            backend
+                .retry
+                .validate()
+                .map_err(|e| format!("Backend {}: {}", name, e))?;
+            for upstream in &backend.upstream_configs {
+                upstream
+                    .validate()
+                    .map_err(|e| format!("Backend {}: {}", name, e))?;
+            }
+            backend
+                .validate_route()
+                .map_err(|e| format!("Backend {}: {}", name, e))?;
+            backend
+                .upgrade
+                .validate()
+                .map_err(|e| format!("Backend {}: {}", name, e))?;
+            backend
+                .grpc
+                .validate()
+                .map_err(|e| format!("Backend {}: {}", name, e))?;
+            if backend.grpc.enabled && backend.protocol == UpstreamProtocol::Http1 {
+                return Err(
+                    format!("Backend {}: grpc requires protocol Http2 or Auto", name).into(),
+                );
+            }
+            if let Some(check) = &backend.health_check {
+                check
+                    .validate()
+                    .map_err(|e| format!("Backend {}: {}", name, e))?;
+            }
+            if let Some(outlier) = &backend.outlier_detection {
+                outlier
+                    .validate()
+                    .map_err(|e| format!("Backend {}: {}", name, e))?;
+            }
+            if let Some(breaker) = &backend.circuit_breaker {
+                breaker
+                    .validate()
+                    .map_err(|e| format!("Backend {}: {}", name, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::Config;
+
+    /// Parses a config for tests from its backends and any other settings,
+    /// with top-level keys first. The token key is the one the benchmarks
+    /// use, so that a runtime can be built from the config.
+    pub fn config(settings: &str) -> Result<Config, String> {
+        Config::parse(&format!(
+            r#"
+            address = "127.0.0.1:0"
+
+            {}
+
+            [auth]
+            algorithm = "ES256"
+            keyfile = "{}/benches/public_key.pem"
+            issuer = "demogorgon"
+            "#,
+            settings,
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .map_err(|e| e.to_string())
+    }
 }
@@ -0,0 +1,413 @@
+use crate::auth::AuthReason;
+use crate::config::Backend;
+use crate::grpc;
+use crate::paths::PathError;
+use crate::upstream::UpstreamError;
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use pages::{ErrorPages, PageVariables};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub mod pages;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Clone, Default, Deserialize, Debug, PartialEq)]
+pub enum ErrorFormat {
+    /// The canonical reason of the status as plain text.
+    #[default]
+    Text,
+    /// RFC 7807 `application/problem+json`, for clients that accept it.
+    Problem,
+}
+
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorConfig {
+    #[serde(default)]
+    pub format: ErrorFormat,
+
+    /// Include internal details, such as JWT validation errors, in bodies.
+    #[serde(default)]
+    pub debug: bool,
+
+    /// Templated error bodies by status code, used for every backend.
+    #[serde(default)]
+    pub pages: ErrorPages,
+}
+
+/// An error to be returned to the client.
+#[derive(Debug)]
+pub struct Problem {
+    pub status: StatusCode,
+    /// A stable, machine readable identifier for the kind of error.
+    pub kind: &'static str,
+    /// A description that is safe to show to the client.
+    pub detail: Option<String>,
+    /// A description that is only shown when debugging is enabled.
+    pub internal: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, kind: &'static str) -> Self {
+        Self {
+            status,
+            kind,
+            detail: None,
+            internal: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn with_internal(mut self, internal: String) -> Self {
+        self.internal = Some(internal);
+        self
+    }
+
+    pub fn not_found() -> Self {
+        Problem::new(StatusCode::NOT_FOUND, "not-found")
+    }
+
+    pub fn bad_gateway(detail: &str) -> Self {
+        Problem::new(StatusCode::BAD_GATEWAY, "bad-gateway").with_detail(detail)
+    }
+
+    pub fn gateway_timeout() -> Self {
+        Problem::new(StatusCode::GATEWAY_TIMEOUT, "gateway-timeout")
+            .with_detail("The backend did not respond in time")
+    }
+
+    pub fn type_uri(&self) -> String {
+        format!("urn:demogorgon:error:{}", self.kind)
+    }
+
+    pub fn title(&self) -> &'static str {
+        self.status.canonical_reason().unwrap_or("Error")
+    }
+}
+
+impl From<&AuthReason> for Problem {
+    fn from(reason: &AuthReason) -> Self {
+        let (kind, detail) = match reason {
+            AuthReason::MissingCredentials(_) => {
+                ("missing-credentials", "A bearer token is required")
+            }
+            AuthReason::BadRequest(_) => ("invalid-request", "The request is malformed"),
+            AuthReason::InvalidCredentials(_) => ("invalid-token", "The access token is invalid"),
+            AuthReason::NotImplemented(_) => ("not-implemented", "Authentication is misconfigured"),
+            AuthReason::InsufficientScope(..) => (
+                "insufficient-scope",
+                "The access token does not grant access to this backend",
+            ),
+            AuthReason::PolicyDenied(_) => (
+                "policy-denied",
+                "The request does not satisfy the backend policy",
+            ),
+            AuthReason::NetworkDenied(_) => (
+                "network-denied",
+                "The backend cannot be reached from this network",
+            ),
+        };
+        Problem::new(reason.status(), kind)
+            .with_detail(detail)
+            .with_internal(reason.to_string())
+    }
+}
+
+impl From<&PathError> for Problem {
+    fn from(err: &PathError) -> Self {
+        Problem::new(StatusCode::BAD_REQUEST, "invalid-path")
+            .with_detail("The request path is not allowed")
+            .with_internal(err.to_string())
+    }
+}
+
+impl From<&UpstreamError> for Problem {
+    fn from(err: &UpstreamError) -> Self {
+        let problem = match err {
+            UpstreamError::Timeout(_) => Problem::gateway_timeout(),
+            UpstreamError::Connect(_) => Problem::bad_gateway("The backend could not be reached"),
+            UpstreamError::Protocol(_) => {
+                Problem::bad_gateway("The backend sent an invalid response")
+            }
+            UpstreamError::RequestBody(_) => {
+                Problem::new(StatusCode::BAD_REQUEST, "invalid-request")
+                    .with_detail("The request body could not be read")
+            }
+            UpstreamError::CircuitOpen(_) => {
+                Problem::new(StatusCode::SERVICE_UNAVAILABLE, "circuit-open")
+                    .with_detail("The backend is temporarily unavailable")
+            }
+            UpstreamError::Target(_) => {
+                Problem::bad_gateway("The request could not be sent to the backend")
+            }
+        };
+        problem.with_internal(err.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct ProblemBody<'a> {
+    #[serde(rename = "type")]
+    type_uri: String,
+    title: &'a str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    request_id: String,
+}
+
+/// Renders errors for a single request in the configured format.
+///
+/// gRPC calls to a backend in gRPC mode get a gRPC status. Otherwise a page
+/// configured on the backend takes precedence over a global page, which
+/// takes precedence over the configured format.
+pub struct ErrorRenderer<'a> {
+    config: &'a ErrorConfig,
+    request_id: Uuid,
+    accepts_json: bool,
+    grpc: bool,
+    backend: Option<(&'a str, &'a Backend)>,
+}
+
+impl<'a> ErrorRenderer<'a> {
+    pub fn new<B>(config: &'a ErrorConfig, req: &Request<B>, request_id: Uuid) -> Self {
+        Self {
+            config,
+            request_id,
+            accepts_json: accepts_json(req.headers().get(ACCEPT)),
+            grpc: grpc::is_grpc(req.headers()),
+            backend: None,
+        }
+    }
+
+    pub fn with_backend(mut self, name: &'a str, backend: &'a Backend) -> Self {
+        self.backend = Some((name, backend));
+        self
+    }
+
+    pub fn render(&self, problem: Problem) -> Response<Body> {
+        let detail = match (self.config.debug, &problem.internal, &problem.detail) {
+            (true, Some(internal), _) => Some(internal.as_str()),
+            (_, _, detail) => detail.as_deref(),
+        };
+
+        // gRPC clients only understand errors sent as a gRPC status.
+        if self.grpc
+            && self
+                .backend
+                .is_some_and(|(_, backend)| backend.grpc.enabled)
+        {
+            let mut response =
+                grpc::error_response(problem.status, detail.unwrap_or(problem.title()));
+            response.headers_mut().insert(
+                REQUEST_ID_HEADER,
+                HeaderValue::from_str(&self.request_id.to_string()).unwrap(),
+            );
+            return response;
+        }
+
+        let page = self
+            .backend
+            .and_then(|(_, backend)| backend.error_pages.get(problem.status))
+            .or_else(|| self.config.pages.get(problem.status));
+
+        let (content_type, body) = match (page, &self.config.format) {
+            (Some(page), _) => {
+                let variables = PageVariables {
+                    status: problem.status,
+                    title: problem.title(),
+                    detail,
+                    request_id: self.request_id,
+                    backend: self.backend.map(|(name, _)| name),
+                    scope: self.backend.map(|(_, backend)| backend.scope.to_string()),
+                };
+                (page.content_type(), page.render(&variables))
+            }
+            (None, ErrorFormat::Problem) if self.accepts_json => {
+                let body = ProblemBody {
+                    type_uri: problem.type_uri(),
+                    title: problem.title(),
+                    status: problem.status.as_u16(),
+                    detail,
+                    request_id: self.request_id.to_string(),
+                };
+                (
+                    "application/problem+json",
+                    serde_json::to_string(&body).unwrap(),
+                )
+            }
+            _ => ("text/plain; charset=utf-8", problem.title().to_string()),
+        };
+
+        Response::builder()
+            .status(problem.status)
+            .header(CONTENT_TYPE, content_type)
+            .header(REQUEST_ID_HEADER, self.request_id.to_string())
+            .body(body.into())
+            .unwrap()
+    }
+}
+
+/// Returns true if the `Accept` header allows a JSON problem body.
+fn accepts_json(accept: Option<&HeaderValue>) -> bool {
+    let accept = match accept.map(|a| a.to_str()) {
+        None => return true,
+        Some(Ok(a)) => a,
+        Some(Err(_)) => return false,
+    };
+    accept.split(',').any(|range| {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let rejected = params.any(|p| {
+            let q = p.trim().strip_prefix("q=").map(|q| q.trim().parse::<f32>());
+            matches!(q, Some(Ok(q)) if q == 0.0)
+        });
+        !rejected
+            && matches!(
+                media.as_str(),
+                "application/problem+json" | "application/json" | "application/*" | "*/*"
+            )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{accepts_json, ErrorConfig, ErrorFormat, ErrorRenderer, Problem};
+    use crate::config::tests::config;
+    use hyper::header::HeaderValue;
+    use hyper::{Request, StatusCode};
+    use uuid::Uuid;
+
+    fn accepts(accept: &str) -> bool {
+        accepts_json(Some(&HeaderValue::from_str(accept).unwrap()))
+    }
+
+    #[test]
+    fn content_negotiation() {
+        assert!(accepts_json(None));
+        assert!(accepts("application/problem+json"));
+        assert!(accepts("application/json"));
+        assert!(accepts("text/html, */*;q=0.8"));
+        assert!(accepts("Application/JSON"));
+        assert!(!accepts("text/html"));
+        assert!(!accepts("text/plain, application/json;q=0"));
+    }
+
+    fn render(config: &ErrorConfig, accept: &str, problem: Problem) -> (String, String) {
+        let req = Request::builder()
+            .header("accept", accept)
+            .body(())
+            .unwrap();
+        let renderer = ErrorRenderer::new(config, &req, Uuid::nil());
+        let response = renderer.render(problem);
+        let content_type = response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = body_to_string(response.into_body());
+        (content_type, body)
+    }
+
+    fn body_to_string(body: hyper::Body) -> String {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let bytes = rt.block_on(hyper::body::to_bytes(body)).unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn problem() -> Problem {
+        Problem::new(StatusCode::UNAUTHORIZED, "invalid-token")
+            .with_detail("The access token is invalid")
+            .with_internal("Invalid token: ExpiredSignature".to_string())
+    }
+
+    #[test]
+    fn text_by_default() {
+        let config = ErrorConfig::default();
+        let (content_type, body) = render(&config, "application/json", problem());
+        assert_eq!(content_type, "text/plain; charset=utf-8");
+        assert_eq!(body, "Unauthorized");
+    }
+
+    #[test]
+    fn problem_json() {
+        let config = ErrorConfig {
+            format: ErrorFormat::Problem,
+            debug: false,
+            ..ErrorConfig::default()
+        };
+        let (content_type, body) = render(&config, "application/json", problem());
+        assert_eq!(content_type, "application/problem+json");
+        assert_eq!(
+            body,
+            concat!(
+                r#"{"type":"urn:demogorgon:error:invalid-token","title":"Unauthorized","#,
+                r#""status":401,"detail":"The access token is invalid","#,
+                r#""request_id":"00000000-0000-0000-0000-000000000000"}"#
+            )
+        );
+
+        let (content_type, body) = render(&config, "text/html", problem());
+        assert_eq!(content_type, "text/plain; charset=utf-8");
+        assert_eq!(body, "Unauthorized");
+    }
+
+    #[test]
+    fn internal_details_only_when_debugging() {
+        let config = ErrorConfig {
+            format: ErrorFormat::Problem,
+            debug: true,
+            ..ErrorConfig::default()
+        };
+        let (_, body) = render(&config, "*/*", problem());
+        assert!(body.contains(r#""detail":"Invalid token: ExpiredSignature""#));
+    }
+
+    #[test]
+    fn grpc_status_for_grpc_backends() {
+        let config = config(
+            r#"
+            [backends.shop]
+            url = "http://shop.internal"
+            scope = "shop:*"
+            protocol = "Http2"
+            grpc = { enabled = true }
+
+            [backends.web]
+            url = "http://web.internal"
+            scope = "web:*"
+            "#,
+        )
+        .unwrap();
+        let req = Request::builder()
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap();
+
+        let renderer = ErrorRenderer::new(&config.errors, &req, Uuid::nil())
+            .with_backend("shop", &config.backends["shop"]);
+        let response = renderer.render(problem());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["grpc-status"], "16");
+        assert_eq!(
+            response.headers()["grpc-message"],
+            "The access token is invalid"
+        );
+        assert!(response.headers().contains_key("x-request-id"));
+
+        let renderer = ErrorRenderer::new(&config.errors, &req, Uuid::nil())
+            .with_backend("web", &config.backends["web"]);
+        assert_eq!(
+            renderer.render(problem()).status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
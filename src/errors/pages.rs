@@ -0,0 +1,261 @@
+use hyper::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+/// The variables that can be used in a template as `{{ name }}`.
+const VARIABLES: &[&str] = &[
+    "status",
+    "title",
+    "detail",
+    "request_id",
+    "backend",
+    "scope",
+];
+
+/// The values of the template variables for a single error.
+pub struct PageVariables<'a> {
+    pub status: StatusCode,
+    pub title: &'a str,
+    pub detail: Option<&'a str>,
+    pub request_id: Uuid,
+    pub backend: Option<&'a str>,
+    pub scope: Option<String>,
+}
+
+impl PageVariables<'_> {
+    fn get(&self, name: &str) -> String {
+        match name {
+            "status" => self.status.as_u16().to_string(),
+            "title" => self.title.to_string(),
+            "detail" => self.detail.unwrap_or("").to_string(),
+            "request_id" => self.request_id.to_string(),
+            "backend" => self.backend.unwrap_or("").to_string(),
+            "scope" => self.scope.clone().unwrap_or_default(),
+            _ => unreachable!("template variables are validated at load"),
+        }
+    }
+}
+
+/// Escapes text for HTML content and attribute values.
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PageType {
+    Html,
+    Json,
+    Text,
+}
+
+impl PageType {
+    fn from_filename(filename: &str) -> Self {
+        match Path::new(filename).extension().and_then(|e| e.to_str()) {
+            Some("html") | Some("htm") => PageType::Html,
+            Some("json") => PageType::Json,
+            _ => PageType::Text,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            PageType::Html => "text/html; charset=utf-8",
+            PageType::Json => "application/json",
+            PageType::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    fn escape(&self, value: &str) -> String {
+        match self {
+            PageType::Html => escape_html(value),
+            PageType::Json => {
+                let quoted = serde_json::to_string(value).unwrap();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            PageType::Text => value.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable(String),
+}
+
+/// A templated error body, loaded from a HTML, JSON or text file.
+#[derive(Clone, Debug)]
+pub struct ErrorPage {
+    page_type: PageType,
+    segments: Vec<Segment>,
+}
+
+impl ErrorPage {
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let template = fs::read_to_string(filename)
+            .map_err(|e| format!("Unable to read error page {}: {}", filename, e))?;
+        ErrorPage::parse(PageType::from_filename(filename), &template)
+            .map_err(|e| format!("Invalid error page {}: {}", filename, e))
+    }
+
+    fn parse(page_type: PageType, template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find("{{") {
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => return Err("Unterminated {{".to_string()),
+            };
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let name = rest[start + 2..end].trim();
+            if !VARIABLES.contains(&name) {
+                return Err(format!(
+                    "Unknown variable '{}', expected one of {:?}",
+                    name, VARIABLES
+                ));
+            }
+            segments.push(Segment::Variable(name.to_string()));
+            rest = &rest[end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+
+        Ok(Self {
+            page_type,
+            segments,
+        })
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.page_type.content_type()
+    }
+
+    pub fn render(&self, variables: &PageVariables) -> String {
+        self.segments
+            .iter()
+            .map(|s| match s {
+                Segment::Text(t) => t.clone(),
+                Segment::Variable(name) => self.page_type.escape(&variables.get(name)),
+            })
+            .collect()
+    }
+}
+
+/// Error pages by status code, loaded and validated with the config.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct ErrorPages(HashMap<StatusCode, ErrorPage>);
+
+impl ErrorPages {
+    pub fn get(&self, status: StatusCode) -> Option<&ErrorPage> {
+        self.0.get(&status)
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for ErrorPages {
+    type Error = String;
+
+    fn try_from(pages: HashMap<String, String>) -> Result<Self, Self::Error> {
+        let mut result = HashMap::new();
+        for (status, filename) in pages {
+            let status = match StatusCode::from_bytes(status.as_bytes()) {
+                Ok(s) if s.is_client_error() || s.is_server_error() => s,
+                _ => return Err(format!("{} is not an error status code", status)),
+            };
+            result.insert(status, ErrorPage::load(&filename)?);
+        }
+        Ok(ErrorPages(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{ErrorPage, ErrorPages, PageType, PageVariables};
+    use hyper::StatusCode;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use uuid::Uuid;
+
+    fn render(page_type: PageType, template: &str) -> String {
+        let page = ErrorPage::parse(page_type, template).unwrap();
+        page.render(&PageVariables {
+            status: StatusCode::FORBIDDEN,
+            title: "Forbidden",
+            detail: Some("<b>\"nope\"</b>"),
+            request_id: Uuid::nil(),
+            backend: Some("hass"),
+            scope: Some("hass:*".to_string()),
+        })
+    }
+
+    #[test]
+    fn variables_are_substituted() {
+        assert_eq!(
+            render(
+                PageType::Text,
+                "{{status}} {{ title }} on {{backend}} needs {{scope}}"
+            ),
+            "403 Forbidden on hass needs hass:*"
+        );
+        assert_eq!(
+            render(PageType::Text, "{{request_id}}"),
+            "00000000-0000-0000-0000-000000000000"
+        );
+        assert_eq!(render(PageType::Text, "no variables"), "no variables");
+    }
+
+    #[test]
+    fn variables_are_escaped() {
+        assert_eq!(
+            render(PageType::Html, "<p>{{detail}}</p>"),
+            "<p>&lt;b&gt;&quot;nope&quot;&lt;/b&gt;</p>"
+        );
+        assert_eq!(
+            render(PageType::Json, r#"{"detail": "{{detail}}"}"#),
+            r#"{"detail": "<b>\"nope\"</b>"}"#
+        );
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!(ErrorPage::parse(PageType::Text, "{{ unknown }}").is_err());
+        assert!(ErrorPage::parse(PageType::Text, "{{ status").is_err());
+    }
+
+    #[test]
+    fn page_types_from_filename() {
+        assert_eq!(PageType::from_filename("403.html"), PageType::Html);
+        assert_eq!(PageType::from_filename("errors/502.json"), PageType::Json);
+        assert_eq!(PageType::from_filename("404.txt"), PageType::Text);
+    }
+
+    #[test]
+    fn invalid_status_codes_are_rejected() {
+        for status in &["200", "302", "abc", "1000"] {
+            let mut pages = HashMap::new();
+            pages.insert(status.to_string(), "Cargo.toml".to_string());
+            assert!(ErrorPages::try_from(pages).is_err());
+        }
+    }
+
+    #[test]
+    fn missing_files_are_rejected() {
+        let mut pages = HashMap::new();
+        pages.insert("404".to_string(), "does/not/exist.html".to_string());
+        assert!(ErrorPages::try_from(pages).is_err());
+    }
+}
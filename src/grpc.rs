@@ -0,0 +1,225 @@
+use crate::auth::scope::ScopeEntry;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+
+/// gRPC mode for a backend.
+///
+/// Errors from the proxy are returned to gRPC clients as a `grpc-status`
+/// and `grpc-message` with HTTP 200, and individual methods can require
+/// scopes beyond the scope of the backend.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub methods: Vec<MethodRule>,
+}
+
+/// A scope required to call a method, given as `/package.Service/Method`,
+/// or as `/package.Service/*` for every method of a service.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MethodRule {
+    pub method: String,
+    pub scope: ScopeEntry,
+}
+
+impl GrpcConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for rule in &self.methods {
+            let valid = match rule
+                .method
+                .strip_prefix('/')
+                .and_then(|m| m.split_once('/'))
+            {
+                Some((service, method)) => {
+                    !service.is_empty() && !method.is_empty() && !method.contains('/')
+                }
+                None => false,
+            };
+            if !valid {
+                return Err(format!(
+                    "grpc method {} must be /package.Service/Method or /package.Service/*",
+                    rule.method
+                ));
+            }
+            if rule.scope.negated {
+                return Err(format!(
+                    "grpc method {} scope cannot be negative",
+                    rule.method
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the scope required to call `method`. A rule for the method
+    /// takes precedence over a rule for its service.
+    pub fn required_scope(&self, method: &str) -> Option<&ScopeEntry> {
+        let service = method.rsplit_once('/').map(|(service, _)| service)?;
+        let exact = self.methods.iter().find(|rule| rule.method == method);
+        exact
+            .or_else(|| {
+                self.methods
+                    .iter()
+                    .find(|rule| rule.method.strip_suffix("/*") == Some(service))
+            })
+            .map(|rule| &rule.scope)
+    }
+}
+
+/// Returns true if a request is a gRPC call, judging by its content type.
+pub fn is_grpc(headers: &HeaderMap<HeaderValue>) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            let value = value.to_ascii_lowercase();
+            value == "application/grpc"
+                || value.starts_with("application/grpc+")
+                || value.starts_with("application/grpc;")
+        })
+}
+
+/// Maps the HTTP status of an error to a gRPC status code, following the
+/// mapping gRPC clients apply to HTTP responses.
+pub fn status_code(status: StatusCode) -> u8 {
+    match status {
+        StatusCode::BAD_REQUEST => 13,  // INTERNAL
+        StatusCode::UNAUTHORIZED => 16, // UNAUTHENTICATED
+        StatusCode::FORBIDDEN => 7,     // PERMISSION_DENIED
+        StatusCode::NOT_FOUND => 12,    // UNIMPLEMENTED
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => 14, // UNAVAILABLE
+        _ => 2,                         // UNKNOWN
+    }
+}
+
+/// Percent-encodes a `grpc-message`, as required for anything other than
+/// printable ASCII.
+fn encode_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Builds a trailers-only gRPC response carrying an error.
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("grpc-status", status_code(status).to_string())
+        .header("grpc-message", encode_message(message))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Returns the gRPC method of a request to a backend, which is the path
+/// passed to the backend.
+pub fn method(upstream_path: &str) -> String {
+    format!("/{}", upstream_path.trim_start_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{encode_message, error_response, is_grpc, method, GrpcConfig, MethodRule};
+    use crate::auth::scope::ScopeEntry;
+    use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+    use hyper::StatusCode;
+    use std::convert::TryFrom;
+
+    fn config(rules: &[(&str, &str)]) -> GrpcConfig {
+        GrpcConfig {
+            enabled: true,
+            methods: rules
+                .iter()
+                .map(|(method, scope)| MethodRule {
+                    method: method.to_string(),
+                    scope: ScopeEntry::try_from(*scope).unwrap(),
+                })
+                .collect(),
+        }
+    }
+
+    fn test_required(config: &GrpcConfig, method: &str, expected: Option<&str>) {
+        assert_eq!(
+            config.required_scope(method).map(|s| s.to_string()),
+            expected.map(|s| s.to_string()),
+            "{}",
+            method
+        );
+    }
+
+    #[test]
+    fn method_rules() {
+        let config = config(&[
+            ("/shop.Orders/*", "shop:orders"),
+            ("/shop.Orders/Cancel", "shop:admin"),
+        ]);
+        test_required(&config, "/shop.Orders/Cancel", Some("shop:admin"));
+        test_required(&config, "/shop.Orders/List", Some("shop:orders"));
+        test_required(&config, "/shop.Stock/List", None);
+        test_required(&config, "/shop.Orders", None);
+    }
+
+    #[test]
+    fn invalid_method_rules_are_rejected() {
+        assert!(config(&[("/a.B/C", "a:b"), ("/a.B/*", "a:b")])
+            .validate()
+            .is_ok());
+        assert!(config(&[("a.B/C", "a:b")]).validate().is_err());
+        assert!(config(&[("/a.B", "a:b")]).validate().is_err());
+        assert!(config(&[("/a.B/C/D", "a:b")]).validate().is_err());
+        assert!(config(&[("/a.B/C", "!a:b")]).validate().is_err());
+    }
+
+    #[test]
+    fn grpc_requests() {
+        for (content_type, expected) in &[
+            ("application/grpc", true),
+            ("application/grpc+proto", true),
+            ("Application/GRPC;charset=utf-8", true),
+            ("application/grpc-web", false),
+            ("application/json", false),
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            assert_eq!(is_grpc(&headers), *expected, "{}", content_type);
+        }
+        assert!(!is_grpc(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn methods_are_taken_from_the_path() {
+        assert_eq!(method("/shop.Orders/List"), "/shop.Orders/List");
+        assert_eq!(method(""), "/");
+    }
+
+    #[test]
+    fn errors_are_trailers_only_responses() {
+        let response = error_response(StatusCode::FORBIDDEN, "Denied");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/grpc");
+        assert_eq!(response.headers()["grpc-status"], "7");
+        assert_eq!(response.headers()["grpc-message"], "Denied");
+        assert_eq!(
+            error_response(StatusCode::UNAUTHORIZED, "").headers()["grpc-status"],
+            "16"
+        );
+        assert_eq!(
+            error_response(StatusCode::BAD_GATEWAY, "").headers()["grpc-status"],
+            "14"
+        );
+        assert_eq!(encode_message("100% ✓\n"), "100%25 %E2%9C%93%0A");
+    }
+}
@@ -0,0 +1,240 @@
+use crate::auth::request_is_authorized;
+use crate::errors::pages::escape_html;
+use crate::runtime::Runtime;
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// A built-in page listing the backends the caller can reach, served at
+/// `path` on every host. No route may serve `path` on any host, and there
+/// can be no default backend, which would serve every path.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct IndexConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+
+    #[serde(default = "default_title")]
+    pub title: String,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_title() -> String {
+    "Demogorgon".to_string()
+}
+
+impl IndexConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.path.starts_with('/') {
+            return Err(format!("index path {} must start with /", self.path));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct IndexEntry<'a> {
+    name: &'a str,
+    url: Option<String>,
+}
+
+/// Returns the backends the request would be authorized for, by name. gRPC
+/// backends are checked as if no particular method were called.
+fn reachable<'a, B>(
+    req: &Request<B>,
+    remote_addr: IpAddr,
+    runtime: &'a Runtime,
+) -> Vec<IndexEntry<'a>> {
+    let mut entries: Vec<IndexEntry> = runtime
+        .config
+        .backends
+        .iter()
+        .filter(|(_, backend)| {
+            request_is_authorized(req, remote_addr, backend, "", runtime).is_ok()
+        })
+        .map(|(name, backend)| IndexEntry {
+            name,
+            url: backend.public_url(),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(b.name));
+    entries
+}
+
+/// Returns true if the `Accept` header names JSON, which browsers do not.
+fn wants_json(accept: Option<&HeaderValue>) -> bool {
+    accept
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.to_ascii_lowercase().contains("application/json"))
+}
+
+fn render_html(title: &str, entries: &[IndexEntry]) -> String {
+    let mut items = String::new();
+    for entry in entries {
+        let name = escape_html(entry.name);
+        match &entry.url {
+            Some(url) => items.push_str(&format!(
+                "<li><a href=\"{}\">{}</a></li>\n",
+                escape_html(url),
+                name
+            )),
+            None => items.push_str(&format!("<li>{}</li>\n", name)),
+        }
+    }
+    let title = escape_html(title);
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n\
+         <body>\n<h1>{}</h1>\n<ul>\n{}</ul>\n</body>\n</html>\n",
+        title, title, items
+    )
+}
+
+/// Renders the index for a request, as JSON if asked for and HTML otherwise.
+pub fn index_response<B>(
+    config: &IndexConfig,
+    req: &Request<B>,
+    remote_addr: IpAddr,
+    runtime: &Runtime,
+) -> Response<Body> {
+    let entries = reachable(req, remote_addr, runtime);
+    let (content_type, body) = match wants_json(req.headers().get(ACCEPT)) {
+        true => ("application/json", serde_json::to_string(&entries).unwrap()),
+        false => (
+            "text/html; charset=utf-8",
+            render_html(&config.title, &entries),
+        ),
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(body.into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{reachable, render_html, IndexEntry};
+    use crate::config::tests::config;
+    use crate::runtime::Runtime;
+    use hyper::Request;
+    use std::net::IpAddr;
+
+    fn runtime() -> Runtime {
+        let config = config(
+            r#"
+            [backends.open]
+            url = "http://open.internal"
+            scope = "open:*"
+            frontend_auth = "NoAuth"
+
+            [backends.lan]
+            url = "http://lan.internal"
+            scope = "lan:*"
+            hosts = ["*.lan.example.com", "lan.example.com"]
+            frontend_auth = { TrustedNetwork = { networks = ["10.0.0.0/8"], scopes = ["lan:*"] } }
+
+            [backends.private]
+            url = "http://private.internal"
+            scope = "private:*"
+            "#,
+        )
+        .unwrap();
+        Runtime::new(config).unwrap()
+    }
+
+    fn config_error(index: &str, backends: &str) -> String {
+        config(&format!("{}\n{}", index, backends)).unwrap_err()
+    }
+
+    #[test]
+    fn index_cannot_share_a_path() {
+        assert_eq!(
+            config_error(
+                "[index]",
+                r#"
+                [backends.grafana]
+                url = "http://grafana.internal"
+                scope = "grafana:*"
+                hosts = ["grafana.example.com"]
+                "#
+            ),
+            "index path / is also served by backend grafana under / on grafana.example.com"
+        );
+        assert_eq!(
+            config_error(
+                "[index]\npath = \"/cats/index\"",
+                r#"
+                [backends.cats]
+                url = "http://cats.internal"
+                scope = "cats:*"
+                routes = [{ regex = "/cats/.*" }]
+                "#
+            ),
+            "index path /cats/index is also served by backend cats under regex /cats/.* on every host"
+        );
+        assert_eq!(
+            config_error(
+                "default_backend = \"cats\"\n[index]\npath = \"/index\"",
+                r#"
+                [backends.cats]
+                url = "http://cats.internal"
+                scope = "cats:*"
+                "#
+            ),
+            "set either index or default_backend, not both"
+        );
+    }
+
+    fn names(remote_addr: &str) -> Vec<(String, Option<String>)> {
+        let runtime = runtime();
+        let req = Request::new(());
+        let remote_addr: IpAddr = remote_addr.parse().unwrap();
+        reachable(&req, remote_addr, &runtime)
+            .into_iter()
+            .map(|entry| (entry.name.to_string(), entry.url))
+            .collect()
+    }
+
+    #[test]
+    fn only_reachable_backends_are_listed() {
+        assert_eq!(
+            names("192.168.1.1"),
+            vec![("open".to_string(), Some("/open".to_string()))]
+        );
+        assert_eq!(
+            names("10.1.2.3"),
+            vec![
+                ("lan".to_string(), Some("//lan.example.com/".to_string())),
+                ("open".to_string(), Some("/open".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn index_is_escaped() {
+        let html = render_html(
+            "<Home>",
+            &[
+                IndexEntry {
+                    name: "a&b",
+                    url: Some("/a\"b".to_string()),
+                },
+                IndexEntry {
+                    name: "c",
+                    url: None,
+                },
+            ],
+        );
+        assert!(html.contains("<title>&lt;Home&gt;</title>"), "{}", html);
+        assert!(
+            html.contains("<li><a href=\"/a&quot;b\">a&amp;b</a></li>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("<li>c</li>"), "{}", html);
+    }
+}
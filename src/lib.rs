@@ -1,92 +1,218 @@
 use crate::auth::{request_is_authorized, AuthReason};
-use crate::proxy::{create_proxied_request, create_proxied_response, request_add_custom_headers};
-use hyper::{Body, Request, Response, StatusCode};
+use crate::errors::{ErrorRenderer, Problem, REQUEST_ID_HEADER};
+use crate::index::index_response;
+use crate::proxy::{
+    create_proxied_request, create_proxied_response, request_add_custom_headers,
+    rewrite_set_cookies, UpstreamPath,
+};
+use crate::redirect::{rewrite_location, Location};
+use crate::runtime::Runtime;
+use hyper::header::{HeaderValue, HOST, LOCATION, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::http::uri::Authority;
+use hyper::{Body, Method, Request, Response};
 use std::net::IpAddr;
+use std::sync::Arc;
+use uuid::Uuid;
 
+pub mod admin;
 pub mod auth;
 pub mod config;
+pub mod errors;
+pub mod grpc;
+pub mod index;
+pub mod paths;
 pub mod proxy;
+pub mod redirect;
+pub mod routing;
+pub mod runtime;
 pub mod tls;
+pub mod upgrade;
+pub mod upstream;
 
 pub const SERVER_VIA: &str = concat!(env!("CARGO_PKG_VERSION"), " Demogorgon");
 
 pub async fn service_handler(
     req: Request<Body>,
     remote_addr: IpAddr,
-    config: config::Config,
+    runtime: Arc<Runtime>,
 ) -> Result<Response<Body>, hyper::Error> {
-    log::debug!("Request to {}", req.uri());
+    let request_id = Uuid::new_v4();
+    log::debug!("Request {} to {}", request_id, req.uri());
 
-    let first = req.uri().path().split('/').nth(1).unwrap();
-    log::debug!("Creating HTTPS client with Cert Auth");
+    let errors = ErrorRenderer::new(&runtime.config.errors, &req, request_id);
 
-    match config.backends.get(first) {
-        Some(backend) => rev_proxy(req, remote_addr, &backend, &config).await,
-        None => Ok(error_response(StatusCode::NOT_FOUND)),
+    let paths = &runtime.config.paths;
+    let path = match paths.normalize(req.uri().path()) {
+        Ok(path) => path,
+        Err(err) => {
+            log::warn!("Refusing request to {}: {}", req.uri().path(), err);
+            return Ok(errors.render(Problem::from(&err)));
+        }
+    };
+
+    if let Some(index) = &runtime.config.index {
+        let read = matches!(*req.method(), Method::GET | Method::HEAD);
+        if read && path == index.path {
+            return Ok(index_response(index, &req, remote_addr, &runtime));
+        }
+    }
+
+    match runtime.route(public_host(&req).as_deref(), &path) {
+        Some((name, backend, upstream_path)) => {
+            let upstream_path = match paths.check_rewrite(&upstream_path) {
+                Ok(path) => UpstreamPath::new(path, req.uri().query()),
+                Err(err) => {
+                    log::warn!("Refusing rewrite of {} to {}: {}", path, upstream_path, err);
+                    return Ok(errors.render(Problem::from(&err)));
+                }
+            };
+            rev_proxy(
+                req,
+                remote_addr,
+                request_id,
+                name,
+                backend,
+                upstream_path,
+                &runtime,
+            )
+            .await
+        }
+        None => Ok(errors.render(Problem::not_found())),
     }
 }
 
 async fn rev_proxy(
-    req: Request<Body>,
+    mut req: Request<Body>,
     remote_addr: IpAddr,
+    request_id: Uuid,
+    name: &str,
     backend: &config::Backend,
-    config: &config::Config,
+    upstream_path: UpstreamPath,
+    runtime: &Runtime,
 ) -> Result<Response<Body>, hyper::Error> {
     let path = req.uri().path().to_string();
+    let public_host = public_host(&req);
+    let method_path = upstream_path.path().to_string();
+    req.extensions_mut().insert(upstream_path);
+    let errors =
+        ErrorRenderer::new(&runtime.config.errors, &req, request_id).with_backend(name, backend);
+    let request_id = HeaderValue::from_str(&request_id.to_string()).unwrap();
 
-    let response = match request_is_authorized(&req, &backend, &config) {
-        Ok(scope) => {
-            let client = backend.get_client();
-            let req = create_proxied_request(remote_addr, &backend, req, &scope)?;
-            let req = request_add_custom_headers(&backend, req);
+    let mut upgrade = None;
+    let response = match request_is_authorized(&req, remote_addr, backend, &method_path, runtime) {
+        Ok(scopes) => {
+            upgrade = match upgrade::requested_protocol(&req) {
+                Some(protocol) if backend.upgrade.enabled => {
+                    Some((protocol, hyper::upgrade::on(&mut req)))
+                }
+                Some(_) => {
+                    log::debug!("Upgrades are not enabled for backend {}", name);
+                    None
+                }
+                None => None,
+            };
+            req.headers_mut()
+                .insert(REQUEST_ID_HEADER, request_id.clone());
+            let req = create_proxied_request(remote_addr, backend, req, &scopes)?;
+            let mut req = request_add_custom_headers(backend, req);
+            if let Some((protocol, _)) = &upgrade {
+                upgrade::set_upgrade_headers(req.headers_mut(), protocol.clone());
+            }
 
-            log::info!("A {} {{{}}} {} {}", remote_addr, scope, req.method(), path);
+            log::info!(
+                "A {} {{{}}} {} {} {}",
+                remote_addr,
+                scopes[0],
+                req.method(),
+                path,
+                request_id.to_str().unwrap()
+            );
 
-            match client.request(req).await {
-                Ok(r) => r,
-                Err(_) => error_response(StatusCode::GATEWAY_TIMEOUT),
+            match upstream::retry::send_with_retries(backend, req).await {
+                Ok(r) => {
+                    let r = rewrite_set_cookies(r, backend, public_host.as_deref());
+                    process_location_header(r, name, backend, runtime, &errors)
+                }
+                Err(err) => {
+                    log::warn!("Backend {} failed: {}", name, err);
+                    let mut response = errors.render(Problem::from(&err));
+                    if let Some(wait) = err.retry_after() {
+                        let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+                        response.headers_mut().insert(RETRY_AFTER, seconds.into());
+                    }
+                    response
+                }
             }
         }
-        Err(ar) => match ar {
-            AuthReason::BadRequest(reason) | AuthReason::NotImplemented(reason) => {
-                log::warn!("D {} {} {}", remote_addr, req.method(), path);
-                log::warn!("Bad Request: {}", reason);
-                error_response(StatusCode::BAD_REQUEST)
-            }
-            AuthReason::InvalidCredentials(jwt_error) => {
-                log::warn!("D {} {} {}", remote_addr, req.method(), path);
-                log::warn!("Invalid token: {}", jwt_error);
-                error_response(StatusCode::UNAUTHORIZED)
-            }
-            AuthReason::InsufficientScope(reason) => {
-                log::warn!("D {} {} {}", remote_addr, req.method(), path);
-                log::warn!("Insufficient scope: {}", reason);
-                error_response(StatusCode::FORBIDDEN)
-            }
-        },
+        Err(ar) => {
+            log::warn!(
+                "D {} {} {} {}",
+                remote_addr,
+                req.method(),
+                path,
+                request_id.to_str().unwrap()
+            );
+            log::warn!("{}", ar);
+            auth_error_response(&ar, &errors)
+        }
     };
-    let response = process_location_header(response);
-    let response = create_proxied_response(response);
+    let mut response = create_proxied_response(response);
+    if let Some((protocol, client)) = upgrade {
+        response = upgrade::proxy_upgrade(response, client, protocol, name, &backend.upgrade);
+    }
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
     Ok(response)
 }
 
-fn process_location_header(response: Response<Body>) -> Response<Body> {
-    match response.status() {
-        StatusCode::MOVED_PERMANENTLY
-        | StatusCode::FOUND
-        | StatusCode::SEE_OTHER
-        | StatusCode::TEMPORARY_REDIRECT
-        | StatusCode::PERMANENT_REDIRECT => {
-            log::warn!("Received a redirect response from backend. Blocking.");
-            error_response(StatusCode::BAD_GATEWAY)
+/// Returns the host the client used to reach the proxy, in lowercase and
+/// without the port, from the `Host` header or, for HTTP/2, the authority.
+fn public_host<B>(req: &Request<B>) -> Option<String> {
+    let host = match req.headers().get(HOST) {
+        Some(host) => host.to_str().ok()?.parse::<Authority>().ok()?,
+        None => req.uri().authority()?.clone(),
+    };
+    let host = host.host().trim_end_matches('.');
+    Some(host.to_ascii_lowercase())
+}
+
+fn process_location_header(
+    mut response: Response<Body>,
+    name: &str,
+    backend: &config::Backend,
+    runtime: &Runtime,
+    errors: &ErrorRenderer,
+) -> Response<Body> {
+    let location = match response.headers().get(LOCATION) {
+        Some(location) => String::from_utf8_lossy(location.as_bytes()).into_owned(),
+        None => return response,
+    };
+    match rewrite_location(&location, backend, &runtime.config.backends) {
+        Location::Keep => response,
+        Location::Rewrite(path) => {
+            log::debug!("Rewriting Location {} to {}", location, path);
+            let path = HeaderValue::from_str(&path).unwrap();
+            response.headers_mut().insert(LOCATION, path);
+            response
+        }
+        Location::Block(reason) if response.status().is_redirection() => {
+            log::warn!("Blocking redirect from backend {}: {}", name, reason);
+            errors.render(
+                Problem::bad_gateway("The backend attempted to redirect to a foreign host")
+                    .with_internal(reason),
+            )
+        }
+        Location::Block(reason) => {
+            log::warn!("Removing Location from backend {}: {}", name, reason);
+            response.headers_mut().remove(LOCATION);
+            response
         }
-        _ => response,
     }
 }
 
-fn error_response(status: StatusCode) -> Response<Body> {
-    Response::builder()
-        .status(status)
-        .body(status.canonical_reason().unwrap().into())
-        .unwrap()
+fn auth_error_response(reason: &AuthReason, errors: &ErrorRenderer) -> Response<Body> {
+    let mut response = errors.render(Problem::from(reason));
+    if let Some(challenge) = reason.challenge() {
+        response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+    }
+    response
 }
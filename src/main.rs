@@ -6,12 +6,17 @@ extern crate log;
 extern crate clap;
 
 use clap::{crate_version, App};
+use demogorgon::admin::admin_handler;
 use demogorgon::config::Config;
+use demogorgon::runtime::Runtime;
 use demogorgon::service_handler;
+use demogorgon::tls::server::serve as serve_tls;
+use demogorgon::upstream::health::spawn_health_checks;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
 use std::process;
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -31,32 +36,70 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     log::info!("Loaded {} backends", config.backends.len());
     for (name, backend) in &config.backends {
-        log::info!("\t /{} -> {}", name, backend.url);
+        let urls: Vec<&str> = backend.upstreams.iter().map(|u| u.url.as_str()).collect();
+        log::info!("\t /{} -> {}", name, urls.join(", "));
         log::info!("\t\tAuthentication: {:?}", backend.frontend_auth);
         log::info!("\t\tAuthorization Scope: {}", backend.scope);
     }
 
-    let config2 = config.clone();
+    let address = config.address;
+    let h2c = config.http2.h2c;
+    let runtime = Arc::new(Runtime::new(config).unwrap_or_else(|err| {
+        error!("Startup Error: {}", err);
+        process::exit(1);
+    }));
+
+    spawn_health_checks(&runtime.config);
+
+    if let Some(admin) = &runtime.config.admin {
+        let runtime = runtime.clone();
+        let service = make_service_fn(move |conn: &AddrStream| {
+            let runtime = runtime.clone();
+            let remote_addr = conn.remote_addr().ip();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    admin_handler(req, remote_addr, runtime.clone())
+                }))
+            }
+        });
+        let server = Server::bind(&admin.address).serve(service);
+        info!("Admin listening on http://{}", admin.address);
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("Admin server error: {}", err);
+            }
+        });
+    }
+
+    let scheme = if runtime.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    info!("Listening on {}://{}", scheme, address);
+    if h2c {
+        info!("Accepting HTTP/2 with prior knowledge (h2c)");
+    }
+
+    if let Some(tls) = &runtime.tls {
+        tls.spawn_reload();
+        return serve_tls(address, tls, runtime.clone(), h2c).await;
+    }
+
     let service = make_service_fn(move |conn: &AddrStream| {
-        // first move it into the closure
-        // closure can be called multiple times, so for each call, we must
-        // clone it and move that clone into the async block
-        let config = config2.clone();
+        // The runtime is immutable, so each connection and request only
+        // needs its own reference to it.
+        let runtime = runtime.clone();
         let remote_addr = conn.remote_addr().ip();
         async move {
-            // async block is only executed once, so just pass it on to the closure
             Ok::<_, hyper::Error>(service_fn(move |_req| {
-                // but this closure may also be called multiple times, so make
-                // a clone for each call, and move the clone into the async block
-                let config = config.clone();
-                async move { service_handler(_req, remote_addr, config).await }
+                let runtime = runtime.clone();
+                async move { service_handler(_req, remote_addr, runtime).await }
             }))
         }
     });
 
-    info!("Listening on http://{}", config.address);
-
-    let server = Server::bind(&config.address).serve(service);
+    let server = Server::bind(&address).http1_only(!h2c).serve(service);
 
     server.await?;
 
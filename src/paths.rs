@@ -0,0 +1,241 @@
+use serde::Deserialize;
+use std::fmt;
+
+/// How request paths are normalised before they are routed.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PathConfig {
+    #[serde(default)]
+    pub dot_segments: DotSegments,
+
+    #[serde(default)]
+    pub encoded_slashes: EncodedSlashes,
+
+    /// Merge runs of slashes into one, so that `/a//b` becomes `/a/b`.
+    #[serde(default)]
+    pub merge_slashes: bool,
+}
+
+/// What to do with `.` and `..` segments, including percent-encoded ones.
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+pub enum DotSegments {
+    /// Resolve them, refusing paths that climb above the root.
+    #[default]
+    Resolve,
+    /// Refuse paths containing them.
+    Reject,
+    /// Pass them on unchanged, leaving backends to resolve them. Paths can
+    /// then reach outside the base path of an upstream.
+    Keep,
+}
+
+/// What to do with percent-encoded slashes, `%2F`.
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+pub enum EncodedSlashes {
+    /// Pass them on encoded, as part of the segment they are in.
+    #[default]
+    Keep,
+    /// Decode them, so that they separate segments.
+    Decode,
+    /// Refuse paths containing them.
+    Reject,
+}
+
+/// Why a request path was refused.
+#[derive(Debug, PartialEq)]
+pub enum PathError {
+    DotSegment,
+    EncodedSlash,
+    Traversal,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::DotSegment => write!(f, "Path contains a dot segment"),
+            PathError::EncodedSlash => write!(f, "Path contains an encoded slash"),
+            PathError::Traversal => write!(f, "Path climbs above the root"),
+        }
+    }
+}
+
+fn is_dot(segment: &str) -> bool {
+    segment == "." || segment.eq_ignore_ascii_case("%2e")
+}
+
+fn is_dot_dot(segment: &str) -> bool {
+    matches!(
+        segment.to_ascii_lowercase().as_str(),
+        ".." | ".%2e" | "%2e." | "%2e%2e"
+    )
+}
+
+fn has_encoded_slash(path: &str) -> bool {
+    path.to_ascii_lowercase().contains("%2f")
+}
+
+fn decode_slashes(path: &str) -> String {
+    path.replace("%2F", "/").replace("%2f", "/")
+}
+
+impl PathConfig {
+    /// Normalises the path of a request. Paths which do not start with `/`,
+    /// such as `*`, are returned unchanged.
+    pub fn normalize(&self, path: &str) -> Result<String, PathError> {
+        if !path.starts_with('/') {
+            return Ok(path.to_string());
+        }
+        let path = match self.encoded_slashes {
+            EncodedSlashes::Keep => path.to_string(),
+            EncodedSlashes::Decode => decode_slashes(path),
+            EncodedSlashes::Reject if has_encoded_slash(path) => {
+                return Err(PathError::EncodedSlash)
+            }
+            EncodedSlashes::Reject => path.to_string(),
+        };
+        let segments: Vec<&str> = path[1..].split('/').collect();
+        let last = segments.len() - 1;
+        let mut output: Vec<&str> = Vec::with_capacity(segments.len());
+        for (i, segment) in segments.into_iter().enumerate() {
+            let dot = is_dot(segment);
+            let dot_dot = is_dot_dot(segment);
+            if self.dot_segments == DotSegments::Reject && (dot || dot_dot) {
+                return Err(PathError::DotSegment);
+            }
+            if self.dot_segments == DotSegments::Resolve && (dot || dot_dot) {
+                if dot_dot && output.pop().is_none() {
+                    return Err(PathError::Traversal);
+                }
+                // A trailing dot segment leaves a trailing slash
+                if i == last {
+                    output.push("");
+                }
+            } else if !(self.merge_slashes && segment.is_empty() && i != last) {
+                output.push(segment);
+            }
+        }
+        Ok(format!("/{}", output.join("/")))
+    }
+
+    /// Checks that a path rewritten by a route does not climb above the
+    /// root, resolving any dot segments it contains.
+    pub fn check_rewrite(&self, path: &str) -> Result<String, PathError> {
+        match self.dot_segments {
+            DotSegments::Keep => Ok(path.to_string()),
+            _ if path.is_empty() => Ok(String::new()),
+            _ => PathConfig {
+                dot_segments: DotSegments::Resolve,
+                encoded_slashes: EncodedSlashes::Keep,
+                merge_slashes: false,
+            }
+            .normalize(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{DotSegments, EncodedSlashes, PathConfig, PathError};
+
+    fn config(dot_segments: DotSegments, encoded_slashes: EncodedSlashes) -> PathConfig {
+        PathConfig {
+            dot_segments,
+            encoded_slashes,
+            merge_slashes: false,
+        }
+    }
+
+    fn test_path(config: &PathConfig, path: &str, expected: Result<&str, PathError>) {
+        assert_eq!(
+            config.normalize(path),
+            expected.map(|p| p.to_string()),
+            "{}",
+            path
+        );
+    }
+
+    #[test]
+    fn paths_are_unchanged_by_default() {
+        let config = PathConfig::default();
+        for path in &[
+            "/",
+            "/cats",
+            "/cats/",
+            "/cats/a/b",
+            "/cats//a",
+            "/cats/a%20b",
+            "/cats/a%2Fb",
+            "/cats/.a/..b/a..",
+            "*",
+        ] {
+            test_path(&config, path, Ok(path));
+        }
+    }
+
+    #[test]
+    fn dot_segments_are_resolved() {
+        let config = PathConfig::default();
+        test_path(&config, "/cats/./a", Ok("/cats/a"));
+        test_path(&config, "/cats/a/../b", Ok("/cats/b"));
+        test_path(&config, "/cats/a/..", Ok("/cats/"));
+        test_path(&config, "/cats/.", Ok("/cats/"));
+        test_path(&config, "/cats/..", Ok("/"));
+        test_path(&config, "/cats/../dogs", Ok("/dogs"));
+        test_path(&config, "/cats/%2e%2E/dogs", Ok("/dogs"));
+        test_path(&config, "/cats/.%2e/dogs", Ok("/dogs"));
+        test_path(&config, "/cats/%2E/a", Ok("/cats/a"));
+        test_path(&config, "/cats//../a", Ok("/cats/a"));
+        test_path(&config, "/..", Err(PathError::Traversal));
+        test_path(&config, "/cats/../../etc", Err(PathError::Traversal));
+        test_path(&config, "/%2e%2e/etc", Err(PathError::Traversal));
+    }
+
+    #[test]
+    fn dot_segments_can_be_rejected_or_kept() {
+        let reject = config(DotSegments::Reject, EncodedSlashes::Keep);
+        test_path(&reject, "/cats/./a", Err(PathError::DotSegment));
+        test_path(&reject, "/cats/%2e%2e", Err(PathError::DotSegment));
+        test_path(&reject, "/cats/a.b/..c", Ok("/cats/a.b/..c"));
+
+        let keep = config(DotSegments::Keep, EncodedSlashes::Keep);
+        test_path(&keep, "/cats/../../etc", Ok("/cats/../../etc"));
+        test_path(&keep, "/cats/./a", Ok("/cats/./a"));
+    }
+
+    #[test]
+    fn encoded_slashes() {
+        let decode = config(DotSegments::Resolve, EncodedSlashes::Decode);
+        test_path(&decode, "/cats/a%2Fb", Ok("/cats/a/b"));
+        test_path(&decode, "/cats/a%2fb", Ok("/cats/a/b"));
+        test_path(&decode, "/cats/..%2F..%2Fetc", Err(PathError::Traversal));
+        test_path(&decode, "/cats/a%2F..%2Fb", Ok("/cats/b"));
+
+        let reject = config(DotSegments::Resolve, EncodedSlashes::Reject);
+        test_path(&reject, "/cats/a%2Fb", Err(PathError::EncodedSlash));
+        test_path(&reject, "/cats/a%2fb", Err(PathError::EncodedSlash));
+        test_path(&reject, "/cats/a%2Eb", Ok("/cats/a%2Eb"));
+    }
+
+    #[test]
+    fn slashes_can_be_merged() {
+        let config = PathConfig {
+            merge_slashes: true,
+            ..PathConfig::default()
+        };
+        test_path(&config, "//cats///a", Ok("/cats/a"));
+        test_path(&config, "/cats/a//", Ok("/cats/a/"));
+        test_path(&config, "//", Ok("/"));
+        test_path(&config, "/cats//..", Ok("/"));
+    }
+
+    #[test]
+    fn rewrites_are_checked() {
+        let resolve = PathConfig::default();
+        assert_eq!(resolve.check_rewrite(""), Ok(String::new()));
+        assert_eq!(resolve.check_rewrite("/a/../b"), Ok("/b".to_string()));
+        assert_eq!(resolve.check_rewrite("/a/../.."), Err(PathError::Traversal));
+        let keep = config(DotSegments::Keep, EncodedSlashes::Keep);
+        assert_eq!(keep.check_rewrite("/../.."), Ok("/../..".to_string()));
+    }
+}
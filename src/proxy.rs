@@ -1,7 +1,7 @@
 use crate::auth::scope;
 use crate::config;
 use crate::SERVER_VIA;
-use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST, VIA};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST, SET_COOKIE, TE, VIA};
 use hyper::{Request, Response, Uri};
 use lazy_static::lazy_static;
 use std::net::IpAddr;
@@ -41,36 +41,40 @@ fn remove_hop_headers(headers: &HeaderMap<HeaderValue>) -> HeaderMap<HeaderValue
     result
 }
 
+/// Returns true if the `TE` header says the client accepts trailers.
+fn wants_trailers(headers: &HeaderMap<HeaderValue>) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            coding
+                .split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .eq_ignore_ascii_case("trailers")
+        })
+}
+
 pub fn create_proxied_request<B>(
     client_ip: IpAddr,
     backend: &config::Backend,
     mut request: Request<B>,
-    scope: &scope::ScopeEntry,
+    scopes: &[scope::ScopeEntry],
 ) -> Result<Request<B>, hyper::Error> {
-    let path_and_query = request
-        .uri()
-        .path_and_query()
-        .map(|x| x.as_str())
-        .unwrap_or("/")
-        .split('/')
-        .skip(2)
-        .fold(String::new(), |a, b| a + "/" + b);
-
-    let uri = format!("{}{}", backend.url, path_and_query);
-    let uri: Uri = uri.parse().unwrap();
-    let host = get_host_from_uri(&uri);
-    *request.uri_mut() = uri;
-
     //Remove sensitive authorisation header
     request.headers_mut().remove("authorization");
 
-    //Add Host Header
-    request
-        .headers_mut()
-        .insert(HOST, HeaderValue::from_str(&host).unwrap());
-
-    // Remove hop header
+    // Remove hop header, except for `TE: trailers`, which gRPC requires
+    let trailers = wants_trailers(request.headers());
     *request.headers_mut() = remove_hop_headers(request.headers());
+    if trailers {
+        request
+            .headers_mut()
+            .insert(TE, HeaderValue::from_static("trailers"));
+    }
 
     // Add forwarding information in the headers
     match request.headers_mut().entry("x-forwarded-for") {
@@ -90,18 +94,92 @@ pub fn create_proxied_request<B>(
         .insert(VIA, HeaderValue::from_static(SERVER_VIA));
 
     // Add Scope Header
-    let scope_to_pass = match backend.scope_header_pass_full {
+    let scope_to_pass = |scope: &scope::ScopeEntry| match backend.scope_header_pass_full {
         true => scope.to_string(),
         false => scope.child.to_string(),
     };
+    let header_value = match backend.scope_header_mode {
+        config::ScopeHeaderMode::First => scope_to_pass(&scopes[0]),
+        config::ScopeHeaderMode::List => {
+            let scopes: Vec<String> = scopes.iter().map(scope_to_pass).collect();
+            scopes.join(",")
+        }
+        config::ScopeHeaderMode::Json => {
+            let scopes: Vec<String> = scopes.iter().map(scope_to_pass).collect();
+            serde_json::to_string(&scopes).unwrap()
+        }
+    };
     request.headers_mut().insert(
         HeaderName::from_bytes(backend.scope_header.as_bytes()).unwrap(),
-        HeaderValue::from_str(&scope_to_pass).unwrap(),
+        HeaderValue::from_str(&header_value).unwrap(),
     );
 
     Ok(request)
 }
 
+/// The path and query passed to the backend of a request, as chosen by the
+/// route serving it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamPath(pub String);
+
+impl UpstreamPath {
+    pub fn new(path: String, query: Option<&str>) -> Self {
+        match query {
+            Some(query) => UpstreamPath(format!("{}?{}", path, query)),
+            None => UpstreamPath(path),
+        }
+    }
+
+    /// Returns the path, without the query.
+    pub fn path(&self) -> &str {
+        self.0.split('?').next().unwrap_or("")
+    }
+}
+
+/// Points a request at an upstream of its backend, joining the path of the
+/// upstream URL with the request's [`UpstreamPath`], or with its whole path
+/// and query if it has none.
+pub fn target_upstream<B>(request: &mut Request<B>, upstream_url: &str) -> Result<(), String> {
+    let upstream: Uri = upstream_url
+        .parse()
+        .map_err(|e| format!("Invalid upstream URL {}: {}", upstream_url, e))?;
+    let path_and_query = match request.extensions().get::<UpstreamPath>() {
+        Some(path) => path.0.clone(),
+        None => request
+            .uri()
+            .path_and_query()
+            .map(|x| x.as_str())
+            .unwrap_or("/")
+            .to_string(),
+    };
+    let path_and_query = join_upstream_path(upstream.path(), &path_and_query);
+
+    let uri = Uri::builder()
+        .scheme(upstream.scheme_str().unwrap_or("http"))
+        .authority(upstream.authority().map(|a| a.as_str()).unwrap_or(""))
+        .path_and_query(path_and_query.as_str())
+        .build()
+        .map_err(|e| format!("Invalid upstream path {}: {}", path_and_query, e))?;
+    let host = get_host_from_uri(&uri);
+    *request.uri_mut() = uri;
+
+    //Add Host Header
+    request
+        .headers_mut()
+        .insert(HOST, HeaderValue::from_str(&host).unwrap());
+    Ok(())
+}
+
+/// Joins the base path of an upstream with the path and query passed to it.
+/// The base path is used as it is when nothing or only a query is passed.
+fn join_upstream_path(base: &str, path_and_query: &str) -> String {
+    match path_and_query.chars().next() {
+        None => base.to_string(),
+        Some('?') => format!("{}{}", base, path_and_query),
+        Some(_) => format!("{}{}", base.trim_end_matches('/'), path_and_query),
+    }
+}
+
 pub fn request_add_custom_headers<B>(
     backend: &config::Backend,
     mut request: Request<B>,
@@ -125,15 +203,108 @@ pub fn create_proxied_response<B>(mut response: Response<B>) -> Response<B> {
     response
 }
 
+/// Rewrites the `Domain` and `Path` of cookies set by a backend, as
+/// configured for the backend.
+///
+/// `Domain` becomes `public_host`, or is removed if the public host is not
+/// known. A `Path` below the base path of an upstream is moved to the same
+/// public path serving it; any other `Path` becomes the prefix of the first
+/// prefix route of the backend.
+pub fn rewrite_set_cookies<B>(
+    mut response: Response<B>,
+    backend: &config::Backend,
+    public_host: Option<&str>,
+) -> Response<B> {
+    if !backend.cookies.domain && !backend.cookies.path {
+        return response;
+    }
+    let cookies: Vec<HeaderValue> = response
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .map(|cookie| match cookie.to_str() {
+            Ok(cookie) => {
+                let cookie = rewrite_set_cookie(cookie, backend, public_host);
+                HeaderValue::from_str(&cookie).unwrap()
+            }
+            Err(_) => cookie.clone(),
+        })
+        .collect();
+    response.headers_mut().remove(SET_COOKIE);
+    for cookie in cookies {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+    response
+}
+
+fn rewrite_set_cookie(
+    cookie: &str,
+    backend: &config::Backend,
+    public_host: Option<&str>,
+) -> String {
+    let mut parts = cookie.split(';');
+    let mut result = vec![parts.next().unwrap_or("").to_string()];
+    for attribute in parts {
+        let (key, value) = match attribute.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (attribute.trim(), ""),
+        };
+        if backend.cookies.domain && key.eq_ignore_ascii_case("domain") {
+            if let Some(host) = public_host {
+                result.push(format!(" Domain={}", host));
+            }
+        } else if backend.cookies.path && key.eq_ignore_ascii_case("path") {
+            result.push(format!(" Path={}", public_cookie_path(value, backend)));
+        } else {
+            result.push(attribute.to_string());
+        }
+    }
+    result.join(";")
+}
+
+fn public_cookie_path(path: &str, backend: &config::Backend) -> String {
+    let rest = backend.upstreams.iter().find_map(|upstream| {
+        let base = upstream
+            .url
+            .parse::<Uri>()
+            .map(|uri| uri.path().trim_end_matches('/').to_string())
+            .ok()?;
+        let rest = path.strip_prefix(&base)?;
+        match rest.chars().next() {
+            None | Some('/') => Some(rest.to_string()),
+            Some(_) => None,
+        }
+    });
+    match rest.as_deref() {
+        Some("") | Some("/") | None => None,
+        Some(rest) => backend.public_path(rest),
+    }
+    .unwrap_or_else(|| backend.public_root())
+}
+
+/// Returns the host of a URI for the `Host` header, with its port if it has
+/// one.
 pub fn get_host_from_uri(uri: &Uri) -> String {
-    uri.authority().unwrap().host().to_string()
+    let authority = uri.authority().unwrap();
+    match authority.port() {
+        Some(port) => format!("{}:{}", authority.host(), port),
+        None => authority.host().to_string(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::get_host_from_uri;
-    use hyper::Uri;
+    use super::{
+        create_proxied_request, get_host_from_uri, rewrite_set_cookie, target_upstream,
+        wants_trailers, UpstreamPath,
+    };
+    use crate::auth::scope::ScopeEntry;
+    use crate::auth::{Authentication, FrontendAuthType};
+    use crate::config::tests::config;
+    use hyper::header::{HeaderMap, HeaderValue, HOST, TE};
+    use hyper::{Request, Uri};
+    use std::convert::TryFrom;
     use std::str::FromStr;
 
     fn test_uri_host(uri: &str, host: &str) {
@@ -141,6 +312,110 @@ mod tests {
         assert_eq!(get_host_from_uri(&uri), host);
     }
 
+    fn test_te(values: &[&str], expected: bool) {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(TE, HeaderValue::from_str(value).unwrap());
+        }
+        assert_eq!(wants_trailers(&headers), expected, "{:?}", values);
+    }
+
+    #[test]
+    fn te_trailers() {
+        test_te(&["trailers"], true);
+        test_te(&["gzip, Trailers"], true);
+        test_te(&["deflate", "trailers;q=1"], true);
+        test_te(&["gzip"], false);
+        test_te(&[], false);
+    }
+
+    #[test]
+    fn upstream_paths() {
+        let path = UpstreamPath::new("/a".to_string(), Some("x=/b"));
+        assert_eq!(path.0, "/a?x=/b");
+        assert_eq!(path.path(), "/a");
+        assert_eq!(UpstreamPath::new(String::new(), None).path(), "");
+    }
+
+    fn test_target(upstream_url: &str, upstream_path: Option<&str>, expected: &str) {
+        let mut request = Request::new(());
+        *request.uri_mut() = Uri::from_static("/cats/a?x=1");
+        if let Some(path) = upstream_path {
+            request
+                .extensions_mut()
+                .insert(UpstreamPath(path.to_string()));
+        }
+        target_upstream(&mut request, upstream_url).unwrap();
+        assert_eq!(
+            request.uri(),
+            expected,
+            "{} {:?}",
+            upstream_url,
+            upstream_path
+        );
+    }
+
+    #[test]
+    fn requests_target_the_upstream_path() {
+        let base = "http://cats.internal/base";
+        test_target(base, Some("/a?x=1"), "http://cats.internal/base/a?x=1");
+        test_target(base, Some("/a/b/"), "http://cats.internal/base/a/b/");
+        test_target(base, Some(""), "http://cats.internal/base");
+        test_target(base, Some("/"), "http://cats.internal/base/");
+        test_target(base, Some("?x=1"), "http://cats.internal/base?x=1");
+        test_target(
+            base,
+            Some("?x=1&y=%2F"),
+            "http://cats.internal/base?x=1&y=%2F",
+        );
+        test_target(base, Some("/a%2Fb"), "http://cats.internal/base/a%2Fb");
+        test_target(base, None, "http://cats.internal/base/cats/a?x=1");
+    }
+
+    #[test]
+    fn requests_target_the_upstream_base_path() {
+        let path = Some("/a?x=1");
+        test_target("http://cats.internal", path, "http://cats.internal/a?x=1");
+        test_target("http://cats.internal/", path, "http://cats.internal/a?x=1");
+        test_target(
+            "http://cats.internal/base/",
+            path,
+            "http://cats.internal/base/a?x=1",
+        );
+        test_target("http://cats.internal/", Some(""), "http://cats.internal/");
+        test_target("http://cats.internal", Some(""), "http://cats.internal/");
+        test_target(
+            "http://cats.internal/base/",
+            Some(""),
+            "http://cats.internal/base/",
+        );
+        test_target(
+            "http://cats.internal",
+            Some("?x=1"),
+            "http://cats.internal/?x=1",
+        );
+        test_target(
+            "https://cats.internal:8443",
+            path,
+            "https://cats.internal:8443/a?x=1",
+        );
+    }
+
+    #[test]
+    fn requests_have_the_upstream_host() {
+        for (url, host) in &[
+            ("http://cats.internal/base", "cats.internal"),
+            ("http://cats.internal:8080/base", "cats.internal:8080"),
+            ("https://user@cats.internal:8443", "cats.internal:8443"),
+            ("http://[::1]:8080", "[::1]:8080"),
+        ] {
+            let mut request = Request::new(());
+            *request.uri_mut() = Uri::from_static("/cats");
+            target_upstream(&mut request, url).unwrap();
+            assert_eq!(request.headers()[HOST], *host, "{}", url);
+        }
+    }
+
     #[test]
     fn host_from_uri() {
         test_uri_host("http://example.com", "example.com");
@@ -178,4 +453,133 @@ mod tests {
         test_uri_host("https://example.com/foo?bees=true", "example.com");
         test_uri_host("https://example.com/foo/bar?bees=true", "example.com");
     }
+
+    fn test_cookie(cookies: &str, cookie: &str, public_host: Option<&str>, expected: &str) {
+        let config = config(&format!(
+            r#"
+            [backends.app]
+            url = "http://app.internal/base"
+            scope = "app:*"
+            cookies = {}
+            "#,
+            cookies
+        ))
+        .unwrap();
+        let backend = &config.backends["app"];
+        assert_eq!(rewrite_set_cookie(cookie, backend, public_host), expected);
+    }
+
+    #[test]
+    fn set_cookie_domain() {
+        let cookies = "{ domain = true }";
+        test_cookie(
+            cookies,
+            "id=1; Domain=app.internal; Path=/base; Secure",
+            Some("proxy.example.com"),
+            "id=1; Domain=proxy.example.com; Path=/base; Secure",
+        );
+        test_cookie(cookies, "id=1; domain=.app.internal", None, "id=1");
+        test_cookie(
+            cookies,
+            "id=1; HttpOnly",
+            Some("proxy.example.com"),
+            "id=1; HttpOnly",
+        );
+    }
+
+    #[test]
+    fn set_cookie_path() {
+        let cookies = "{ path = true }";
+        let host = Some("proxy.example.com");
+        test_cookie(cookies, "id=1; Path=/base/app", host, "id=1; Path=/app/app");
+        test_cookie(cookies, "id=1; Path=/base", host, "id=1; Path=/app");
+        test_cookie(cookies, "id=1; Path=/base/", host, "id=1; Path=/app");
+        test_cookie(cookies, "id=1; Path=/", host, "id=1; Path=/app");
+        test_cookie(cookies, "id=1; Path=/basement", host, "id=1; Path=/app");
+        test_cookie(
+            cookies,
+            "id=1; PATH=/base/x; Domain=app.internal",
+            host,
+            "id=1; Path=/app/x; Domain=app.internal",
+        );
+    }
+
+    #[test]
+    fn set_cookie_path_at_the_root() {
+        let cookies = "{ path = true }\n            hosts = [\"app.example.com\"]";
+        let host = Some("app.example.com");
+        test_cookie(cookies, "id=1; Path=/base/app", host, "id=1; Path=/app");
+        test_cookie(cookies, "id=1; Path=/base", host, "id=1; Path=/");
+        test_cookie(cookies, "id=1; Path=/other", host, "id=1; Path=/");
+    }
+
+    #[test]
+    fn set_cookie_unchanged_by_default() {
+        test_cookie(
+            "{}",
+            "id=a=b; Domain=app.internal; Path=/base",
+            Some("proxy.example.com"),
+            "id=a=b; Domain=app.internal; Path=/base",
+        );
+    }
+
+    /// Returns the scope header sent to a backend for a token with several
+    /// scopes, some of them denied.
+    fn scope_header(mode: &str, pass_full: bool) -> String {
+        let config = config(&format!(
+            r#"
+            [backends.hass]
+            url = "http://hass.internal"
+            scope = "hass:*"
+            deny_scopes = ["hass:guest"]
+            scope_header_mode = "{}"
+            scope_header_pass_full = {}
+            "#,
+            mode, pass_full
+        ))
+        .unwrap();
+        let backend = &config.backends["hass"];
+        let authentication = Authentication {
+            id: None,
+            auth_type: FrontendAuthType::Token,
+            scopes: [
+                "hass:*",
+                "hass:lights",
+                "cats:cat",
+                "hass:guest",
+                "hass:admin",
+                "!hass:admin",
+                "*:read",
+            ]
+            .iter()
+            .map(|s| ScopeEntry::try_from(*s).unwrap())
+            .collect(),
+            claims: Default::default(),
+        };
+        let scopes = authentication.authorize(backend).ok().unwrap();
+        let request = create_proxied_request(
+            "10.0.0.1".parse().unwrap(),
+            backend,
+            Request::new(()),
+            &scopes,
+        )
+        .unwrap();
+        request.headers()["X-Demogorgon-Scope"]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn scope_header_modes() {
+        assert_eq!(scope_header("First", false), "*");
+        assert_eq!(scope_header("List", false), "*,lights,read");
+        assert_eq!(scope_header("Json", false), r#"["*","lights","read"]"#);
+        assert_eq!(scope_header("First", true), "hass:*");
+        assert_eq!(scope_header("List", true), "hass:*,hass:lights,*:read");
+        assert_eq!(
+            scope_header("Json", true),
+            r#"["hass:*","hass:lights","*:read"]"#
+        );
+    }
 }
@@ -0,0 +1,324 @@
+use crate::config::Backend;
+use hyper::Uri;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// What to do with a `Location` header that points outside the backend.
+#[derive(Clone, Default, Deserialize, Debug)]
+pub enum ForeignRedirects {
+    /// Answer with 502 instead of redirecting.
+    #[default]
+    Block,
+    /// Pass the redirect to the client unchanged.
+    Allow,
+    /// Pass redirects to the listed hosts unchanged, and block the rest.
+    AllowHosts(Vec<String>),
+    /// Rewrite redirects to the upstreams of other backends to the public
+    /// prefix of that backend, and block the rest.
+    Rewrite,
+}
+
+/// How a `Location` header from a backend should be passed to the client.
+#[derive(Debug, PartialEq)]
+pub enum Location {
+    /// Pass the header unchanged.
+    Keep,
+    /// Replace the header with this public path.
+    Rewrite(String),
+    /// Do not pass the header on, for this reason.
+    Block(String),
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+/// Returns the part of an absolute URL after the base path of `upstream`, if
+/// the URL is at or below it.
+fn strip_upstream<'a>(url: &Uri, path_and_query: &'a str, upstream: &str) -> Option<&'a str> {
+    let upstream: Uri = upstream.parse().ok()?;
+    let scheme = url.scheme_str()?;
+    if upstream.scheme_str() != Some(scheme) {
+        return None;
+    }
+    let (authority, upstream_authority) = (url.authority()?, upstream.authority()?);
+    if !authority
+        .host()
+        .eq_ignore_ascii_case(upstream_authority.host())
+        || authority.port_u16().or_else(|| default_port(scheme))
+            != upstream_authority
+                .port_u16()
+                .or_else(|| default_port(scheme))
+    {
+        return None;
+    }
+
+    let base = upstream.path().trim_end_matches('/');
+    let rest = path_and_query.strip_prefix(base)?;
+    match rest.chars().next() {
+        None | Some('/') | Some('?') => Some(rest),
+        Some(_) => None,
+    }
+}
+
+/// Maps a URL below one of the backend's upstreams to the public path
+/// serving it.
+fn public_path(url: &Uri, path_and_query: &str, backend: &Backend) -> Option<String> {
+    backend
+        .upstreams
+        .iter()
+        .filter_map(|upstream| strip_upstream(url, path_and_query, &upstream.url))
+        .find_map(|rest| backend.public_path(rest))
+}
+
+/// Maps a URL below one of the upstreams of another backend to where that
+/// backend is served.
+fn public_url(url: &Uri, path_and_query: &str, backend: &Backend) -> Option<String> {
+    backend.public_location(&public_path(url, path_and_query, backend)?)
+}
+
+/// Returns the scheme of a URL, if it is absolute.
+fn scheme(location: &str) -> Option<&str> {
+    let (scheme, _) = location.split_once("://")?;
+    let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    Some(scheme).filter(|_| valid)
+}
+
+/// Decides how a `Location` header sent by `backend` should reach the
+/// client.
+///
+/// Locations at or below the URL of one of the backend's upstreams are
+/// rewritten to the backend's public prefix, as are paths that resolve
+/// below it. Relative references are kept, as the client resolves them
+/// against the public URL. Anything else is foreign and handled by the
+/// backend's `foreign_redirects` policy.
+pub fn rewrite_location(
+    location: &str,
+    backend: &Backend,
+    backends: &HashMap<String, Backend>,
+) -> Location {
+    let (location, fragment) = match location.find('#') {
+        Some(i) => location.split_at(i),
+        None => (location, ""),
+    };
+    let upstream: Uri = match backend.upstreams.first().map(|u| u.url.parse()) {
+        Some(Ok(uri)) => uri,
+        _ => return Location::Block("The backend has no valid upstream".to_string()),
+    };
+
+    let absolute = if location.starts_with("//") {
+        format!("{}:{}", upstream.scheme_str().unwrap_or("http"), location)
+    } else if location.starts_with('/') {
+        format!(
+            "{}://{}{}",
+            upstream.scheme_str().unwrap_or("http"),
+            upstream.authority().map(|a| a.as_str()).unwrap_or(""),
+            location
+        )
+    } else if scheme(location).is_some() {
+        location.to_string()
+    } else {
+        return Location::Keep;
+    };
+    let url: Uri = match absolute.parse() {
+        Ok(url) => url,
+        Err(_) => return Location::Block(format!("Invalid location {}", location)),
+    };
+    let path_and_query = url.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    if let Some(path) = public_path(&url, path_and_query, backend) {
+        return Location::Rewrite(path + fragment);
+    }
+
+    let host = url.host().unwrap_or("");
+    match &backend.foreign_redirects {
+        ForeignRedirects::Allow => Location::Keep,
+        ForeignRedirects::AllowHosts(hosts)
+            if hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) =>
+        {
+            Location::Keep
+        }
+        ForeignRedirects::Rewrite => {
+            let mut names: Vec<&String> = backends.keys().collect();
+            names.sort();
+            names
+                .into_iter()
+                .find_map(|name| public_url(&url, path_and_query, &backends[name]))
+                .map(|path| Location::Rewrite(path + fragment))
+                .unwrap_or_else(|| Location::Block(format!("Redirect to foreign URL {}", absolute)))
+        }
+        _ => Location::Block(format!("Redirect to foreign URL {}", absolute)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{rewrite_location, Location};
+    use crate::config::Config;
+
+    fn config(foreign_redirects: &str) -> Config {
+        crate::config::tests::config(&format!(
+            r#"
+            [backends.app]
+            scope = "app:*"
+            upstreams = [{{ url = "http://app.internal:8080/base" }}, {{ url = "https://app2.internal/" }}]
+            foreign_redirects = {}
+
+            [backends.login]
+            url = "https://login.internal/sso"
+            scope = "login:*"
+
+            [backends.docs]
+            url = "http://docs.internal"
+            scope = "docs:*"
+            hosts = ["*.docs.example.com", "Docs.example.com"]
+            "#,
+            foreign_redirects
+        ))
+        .unwrap()
+    }
+
+    fn test_location(foreign_redirects: &str, location: &str, expected: Location) {
+        let config = config(foreign_redirects);
+        let backend = &config.backends["app"];
+        assert_eq!(
+            rewrite_location(location, backend, &config.backends),
+            expected,
+            "{}",
+            location
+        );
+    }
+
+    fn rewritten(path: &str) -> Location {
+        Location::Rewrite(path.to_string())
+    }
+
+    fn blocked(location: &str) -> Location {
+        Location::Block(format!("Redirect to foreign URL {}", location))
+    }
+
+    #[test]
+    fn own_upstreams_are_rewritten() {
+        test_location(
+            "\"Block\"",
+            "http://app.internal:8080/base",
+            rewritten("/app"),
+        );
+        test_location(
+            "\"Block\"",
+            "http://app.internal:8080/base/",
+            rewritten("/app/"),
+        );
+        test_location(
+            "\"Block\"",
+            "http://APP.internal:8080/base/a/b?c=d#e",
+            rewritten("/app/a/b?c=d#e"),
+        );
+        test_location(
+            "\"Block\"",
+            "http://app.internal:8080/base?x",
+            rewritten("/app?x"),
+        );
+        test_location(
+            "\"Block\"",
+            "https://app2.internal/login",
+            rewritten("/app/login"),
+        );
+        test_location(
+            "\"Block\"",
+            "https://app2.internal:443/",
+            rewritten("/app/"),
+        );
+    }
+
+    #[test]
+    fn paths_are_resolved_against_the_upstream() {
+        test_location("\"Block\"", "/base/login", rewritten("/app/login"));
+        test_location(
+            "\"Block\"",
+            "//app.internal:8080/base/x",
+            rewritten("/app/x"),
+        );
+        test_location(
+            "\"Block\"",
+            "/other",
+            blocked("http://app.internal:8080/other"),
+        );
+        test_location(
+            "\"Block\"",
+            "/basement",
+            blocked("http://app.internal:8080/basement"),
+        );
+    }
+
+    #[test]
+    fn relative_references_are_kept() {
+        test_location("\"Block\"", "login", Location::Keep);
+        test_location("\"Block\"", "../x?y", Location::Keep);
+        test_location("\"Block\"", "?page=2", Location::Keep);
+    }
+
+    #[test]
+    fn foreign_redirect_policies() {
+        let foreign = "https://example.com/x";
+        test_location("\"Block\"", foreign, blocked(foreign));
+        test_location("\"Allow\"", foreign, Location::Keep);
+        test_location(
+            "\"Block\"",
+            "http://app.internal:9999/base",
+            blocked("http://app.internal:9999/base"),
+        );
+        test_location(
+            "\"Block\"",
+            "https://app.internal:8080/base",
+            blocked("https://app.internal:8080/base"),
+        );
+        test_location(
+            "{ AllowHosts = [\"example.com\"] }",
+            foreign,
+            Location::Keep,
+        );
+        test_location(
+            "{ AllowHosts = [\"example.org\"] }",
+            foreign,
+            blocked(foreign),
+        );
+    }
+
+    #[test]
+    fn foreign_redirects_to_other_backends() {
+        test_location(
+            "\"Rewrite\"",
+            "https://login.internal/sso/start?next=/app",
+            rewritten("/login/start?next=/app"),
+        );
+        test_location(
+            "\"Block\"",
+            "https://login.internal/sso/start",
+            blocked("https://login.internal/sso/start"),
+        );
+        test_location(
+            "\"Rewrite\"",
+            "http://docs.internal/guide?page=2",
+            rewritten("//docs.example.com/guide?page=2"),
+        );
+        test_location(
+            "\"Rewrite\"",
+            "http://docs.internal?page=2",
+            rewritten("//docs.example.com/?page=2"),
+        );
+        test_location(
+            "\"Rewrite\"",
+            "https://example.com/",
+            blocked("https://example.com/"),
+        );
+    }
+}
@@ -0,0 +1,759 @@
+use crate::config::Backend;
+use regex::{Captures, Regex};
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A route to a backend, matching request paths either below a `prefix` or
+/// against a `regex`.
+///
+/// The path passed to the backend is the rest of the path after the prefix,
+/// appended to `rewrite` if set. For a regex route, `rewrite` is a template
+/// which may refer to the captures of the regex as `${1}` or `${name}`, and
+/// the whole path is passed on if it is not set.
+#[derive(Clone, Default, serde::Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub prefix: Option<String>,
+    pub regex: Option<PathRegex>,
+    pub rewrite: Option<String>,
+}
+
+/// A regex matched against the whole request path.
+#[derive(Clone, Debug)]
+pub struct PathRegex {
+    source: String,
+    regex: Regex,
+}
+
+impl PathRegex {
+    fn new(source: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            source: source.to_string(),
+            regex: Regex::new(&format!("^(?:{})$", source))?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for PathRegex {
+    fn deserialize<D>(deserializer: D) -> Result<PathRegex, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PathRegexVisitor)
+    }
+}
+
+struct PathRegexVisitor;
+
+impl<'de> Visitor<'de> for PathRegexVisitor {
+    type Value = PathRegex;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a valid regex")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        PathRegex::new(value).map_err(de::Error::custom)
+    }
+}
+
+fn valid_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~%".contains(c))
+}
+
+/// Returns true if `path` is `/`, or one or more `/segments`.
+fn valid_path(path: &str) -> bool {
+    path == "/"
+        || path
+            .strip_prefix('/')
+            .is_some_and(|segments| segments.split('/').all(valid_segment))
+}
+
+/// Joins a path to the rest of a path after a prefix, which may be empty or
+/// start with `/` or `?`.
+fn join(path: &str, rest: &str) -> String {
+    match (path.trim_end_matches('/'), rest) {
+        ("", "") => "/".to_string(),
+        ("", rest) if rest.starts_with('?') => format!("/{}", rest),
+        (path, rest) => format!("{}{}", path, rest),
+    }
+}
+
+/// Returns the part of `path` after `prefix`, if `path` is at or below it.
+/// Everything is below the root, which is an empty prefix.
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    match rest.chars().next() {
+        None | Some('/') | Some('?') => Some(rest),
+        Some(_) if prefix.is_empty() => Some(rest),
+        Some(_) => None,
+    }
+}
+
+impl RouteConfig {
+    /// Returns a route serving everything below `prefix`.
+    pub fn with_prefix(prefix: &str) -> Self {
+        Self {
+            prefix: Some(prefix.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.prefix, &self.regex) {
+            (Some(_), Some(_)) => return Err("route has both prefix and regex".to_string()),
+            (None, None) => return Err("route needs a prefix or regex".to_string()),
+            (Some(prefix), None) if !valid_path(prefix) => {
+                return Err(format!("route prefix {} is not a valid path", prefix));
+            }
+            (Some(_), None) => {
+                if let Some(rewrite) = self.rewrite.as_ref().filter(|r| !valid_path(r)) {
+                    return Err(format!("route rewrite {} is not a valid path", rewrite));
+                }
+            }
+            (None, Some(_)) => {
+                if let Some(rewrite) = self.rewrite.as_ref().filter(|r| !r.starts_with('/')) {
+                    return Err(format!("route rewrite {} must start with /", rewrite));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the prefix of a prefix route, which is empty for the root.
+    pub fn public_prefix(&self) -> Option<&str> {
+        self.prefix.as_deref().map(|p| p.trim_end_matches('/'))
+    }
+
+    /// Returns the path passed to the backend for a request path served by
+    /// the route.
+    fn upstream_path(&self, path: &str) -> Option<String> {
+        if let Some(regex) = &self.regex {
+            let captures = regex.regex.captures(path)?;
+            return Some(match &self.rewrite {
+                Some(template) => expand(&captures, template),
+                None => path.to_string(),
+            });
+        }
+        let rest = strip_prefix(path, self.public_prefix()?)?;
+        Some(match &self.rewrite {
+            Some(rewrite) => join(rewrite, rest),
+            None => rest.to_string(),
+        })
+    }
+
+    /// Maps the rest of a path after the URL of an upstream back to the
+    /// public path serving it, which is only possible for prefix routes.
+    pub fn public_path(&self, upstream_rest: &str) -> Option<String> {
+        let prefix = self.public_prefix()?;
+        let rest = match &self.rewrite {
+            Some(rewrite) => strip_prefix(upstream_rest, rewrite.trim_end_matches('/'))?,
+            None => upstream_rest,
+        };
+        Some(join(prefix, rest))
+    }
+
+    fn describe(&self) -> String {
+        match (self.public_prefix(), &self.regex) {
+            (Some(""), _) => "/".to_string(),
+            (Some(prefix), _) => prefix.to_string(),
+            (None, Some(regex)) => format!("regex {}", regex.source),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+fn expand(captures: &Captures, template: &str) -> String {
+    let mut path = String::new();
+    captures.expand(template, &mut path);
+    path
+}
+
+/// The hosts a route or a listener certificate is served on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HostPattern {
+    Any,
+    Exact(String),
+    /// Any host one label below the domain, as with TLS certificates.
+    Subdomain(String),
+}
+
+impl HostPattern {
+    /// Parses a host name, or `*.` followed by one, returning `None` if it
+    /// is not valid.
+    pub fn parse(host: &str) -> Option<Self> {
+        let host = host.to_ascii_lowercase();
+        let (name, wildcard) = match host.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (host.as_str(), false),
+        };
+        let valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        match (valid, wildcard) {
+            (false, _) => None,
+            (true, true) => Some(HostPattern::Subdomain(name.to_string())),
+            (true, false) => Some(HostPattern::Exact(name.to_string())),
+        }
+    }
+
+    /// Returns true if the pattern matches `host`, which should be
+    /// lowercase and without a port.
+    pub fn matches(&self, host: Option<&str>) -> bool {
+        match (self, host) {
+            (HostPattern::Any, _) => true,
+            (HostPattern::Exact(exact), Some(host)) => exact == host,
+            (HostPattern::Subdomain(domain), Some(host)) => host
+                .split_once('.')
+                .is_some_and(|(label, parent)| !label.is_empty() && parent == domain),
+            (_, None) => false,
+        }
+    }
+
+    /// Exact hosts are more specific than subdomains, and subdomains of
+    /// longer domains are more specific than those of shorter ones.
+    pub fn specificity(&self) -> (u8, usize) {
+        match self {
+            HostPattern::Any => (0, 0),
+            HostPattern::Subdomain(domain) => (1, domain.len()),
+            HostPattern::Exact(_) => (2, 0),
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Any => write!(f, "every host"),
+            HostPattern::Exact(host) => write!(f, "{}", host),
+            HostPattern::Subdomain(domain) => write!(f, "*.{}", domain),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Route {
+    host: HostPattern,
+    config: RouteConfig,
+    backend: String,
+    index: usize,
+}
+
+impl Route {
+    /// Routes are tried from the most specific host. For each host, regex
+    /// routes are tried first, then prefix routes from the longest prefix.
+    /// Ties are broken by backend name and the order of its routes, so the
+    /// order does not depend on how the config is read.
+    fn order(&self) -> impl Ord + '_ {
+        (
+            Reverse(self.host.specificity()),
+            self.config.prefix.is_some(),
+            Reverse(self.config.public_prefix().map_or(0, |p| p.len())),
+            &self.backend,
+            self.index,
+        )
+    }
+
+    fn conflicts(&self, other: &Route) -> bool {
+        if self.host != other.host {
+            return false;
+        }
+        match (&self.config.regex, &other.config.regex) {
+            (Some(a), Some(b)) => a.source == b.source,
+            (None, None) => self.config.public_prefix() == other.config.public_prefix(),
+            _ => false,
+        }
+    }
+}
+
+/// The routes to every backend, in the order they are tried, and the
+/// backend serving requests that none of them match.
+#[derive(Clone, Debug, Default)]
+pub struct RouteTable {
+    routes: Vec<Route>,
+    catch_all: Option<String>,
+}
+
+impl RouteTable {
+    /// Builds the routes of the backends, failing if two routes serve the
+    /// same prefix or regex on the same host, or if the `catch_all` backend
+    /// could never be reached.
+    pub fn new(
+        backends: &HashMap<String, Backend>,
+        catch_all: Option<&str>,
+    ) -> Result<Self, String> {
+        let mut routes: Vec<Route> = Vec::new();
+        for (name, backend) in backends {
+            let hosts = match backend.hosts.is_empty() {
+                true => vec![HostPattern::Any],
+                false => backend
+                    .hosts
+                    .iter()
+                    .map(|h| {
+                        HostPattern::parse(h)
+                            .ok_or_else(|| format!("host {} is not a valid host name", h))
+                    })
+                    .collect::<Result<_, _>>()?,
+            };
+            for host in hosts {
+                for (index, config) in backend.routes.iter().enumerate() {
+                    let route = Route {
+                        host: host.clone(),
+                        config: config.clone(),
+                        backend: name.clone(),
+                        index,
+                    };
+                    if let Some(other) = routes.iter().find(|r| r.conflicts(&route)) {
+                        let backends = match other.backend.cmp(name) {
+                            Ordering::Equal => format!("Backend {} is", name),
+                            Ordering::Less => {
+                                format!("Backends {} and {} are", other.backend, name)
+                            }
+                            Ordering::Greater => {
+                                format!("Backends {} and {} are", name, other.backend)
+                            }
+                        };
+                        return Err(format!(
+                            "{} served twice under {} on {}",
+                            backends,
+                            config.describe(),
+                            host
+                        ));
+                    }
+                    routes.push(route);
+                }
+            }
+        }
+        routes.sort_by(|a, b| a.order().cmp(&b.order()));
+        if let Some(catch_all) = catch_all {
+            let root = routes
+                .iter()
+                .find(|r| r.host == HostPattern::Any && r.config.public_prefix() == Some(""));
+            if let Some(root) = root {
+                return Err(format!(
+                    "default_backend {} is never used, as backend {} is served under / on every host",
+                    catch_all, root.backend
+                ));
+            }
+        }
+        Ok(Self {
+            routes,
+            catch_all: catch_all.map(|name| name.to_string()),
+        })
+    }
+
+    /// Returns the name of the backend serving a request for `path` on
+    /// `host`, which should be lowercase and without a port, and the path to
+    /// pass to the backend. The catch-all backend is passed the whole path.
+    pub fn find(&self, host: Option<&str>, path: &str) -> Option<(&str, String)> {
+        self.routes
+            .iter()
+            .filter(|r| r.host.matches(host))
+            .find_map(|r| Some((r.backend.as_str(), r.config.upstream_path(path)?)))
+            .or_else(|| {
+                let catch_all = self.catch_all.as_deref()?;
+                Some((catch_all, path.to_string()))
+            })
+    }
+
+    /// Returns the route serving `path` on some host, as a backend name and
+    /// a description of the route, ignoring the catch-all backend.
+    pub fn serving(&self, path: &str) -> Option<(&str, String)> {
+        self.routes
+            .iter()
+            .find(|r| r.config.upstream_path(path).is_some())
+            .map(|r| {
+                let route = format!("{} on {}", r.config.describe(), r.host);
+                (r.backend.as_str(), route)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{join, strip_prefix, HostPattern, RouteConfig};
+    use crate::config::tests::config;
+    use crate::config::Config;
+
+    fn routes() -> Config {
+        config(
+            r#"
+            [backends.cats]
+            url = "http://cats.internal"
+            scope = "cats:*"
+
+            [backends.app]
+            url = "http://app.internal"
+            scope = "app:*"
+            hosts = ["app.example.com"]
+
+            [backends.app-api]
+            url = "http://api.internal"
+            scope = "app:*"
+            hosts = ["app.example.com"]
+            prefix = "/api"
+
+            [backends.tenants]
+            url = "http://tenants.internal"
+            scope = "tenants:*"
+            hosts = ["*.example.com"]
+
+            [backends.eu-tenants]
+            url = "http://eu.internal"
+            scope = "tenants:*"
+            hosts = ["*.eu.example.com"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn test_route(config: &Config, host: Option<&str>, path: &str, expected: Option<&str>) {
+        assert_eq!(
+            config.routes.find(host, path).map(|(name, _)| name),
+            expected,
+            "{:?} {}",
+            host,
+            path
+        );
+    }
+
+    fn test_upstream(config: &Config, path: &str, expected: Option<(&str, &str)>) {
+        assert_eq!(
+            config.routes.find(None, path),
+            expected.map(|(name, path)| (name, path.to_string())),
+            "{}",
+            path
+        );
+    }
+
+    fn rewrites() -> Config {
+        config(
+            r#"
+            [backends.api]
+            url = "http://api.internal"
+            scope = "api:*"
+
+            [backends.billing]
+            url = "http://billing.internal"
+            scope = "billing:*"
+            routes = [
+                { prefix = "/api/v2/billing", rewrite = "/v2" },
+                { prefix = "/billing" },
+            ]
+
+            [backends.avatars]
+            url = "http://avatars.internal"
+            scope = "avatars:*"
+            routes = [
+                { regex = '/api/users/(?P<id>\d+)/avatar', rewrite = "/avatars/${id}.png" },
+                { regex = '/api/(v\d+)/avatars/(.+)', rewrite = "/${1}/${2}" },
+                { regex = '/static/.*\.png' },
+            ]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn prefix_routes() {
+        let config = rewrites();
+        test_upstream(&config, "/api/v2/billing", Some(("billing", "/v2")));
+        test_upstream(&config, "/api/v2/billing/x", Some(("billing", "/v2/x")));
+        test_upstream(&config, "/api/v2/billings", Some(("api", "/v2/billings")));
+        test_upstream(&config, "/api/v2", Some(("api", "/v2")));
+        test_upstream(&config, "/api", Some(("api", "")));
+        test_upstream(&config, "/billing/a", Some(("billing", "/a")));
+    }
+
+    #[test]
+    fn regex_routes() {
+        let config = rewrites();
+        test_upstream(
+            &config,
+            "/api/users/42/avatar",
+            Some(("avatars", "/avatars/42.png")),
+        );
+        test_upstream(
+            &config,
+            "/api/users/x/avatar",
+            Some(("api", "/users/x/avatar")),
+        );
+        test_upstream(
+            &config,
+            "/api/users/42/avatar/x",
+            Some(("api", "/users/42/avatar/x")),
+        );
+        test_upstream(&config, "/api/v3/avatars/a/b", Some(("avatars", "/v3/a/b")));
+        test_upstream(
+            &config,
+            "/static/a/b.png",
+            Some(("avatars", "/static/a/b.png")),
+        );
+        test_upstream(&config, "/static/a/b.png.txt", None);
+    }
+
+    #[test]
+    fn routes_are_ordered_deterministically() {
+        for _ in 0..10 {
+            let config = config(
+                r#"
+                [backends.a]
+                url = "http://a.internal"
+                scope = "a:*"
+                routes = [{ regex = "/x/.*" }]
+
+                [backends.b]
+                url = "http://b.internal"
+                scope = "b:*"
+                routes = [{ regex = "/x/y.*" }, { prefix = "/x/y" }]
+
+                [backends.c]
+                url = "http://c.internal"
+                scope = "c:*"
+                routes = [{ prefix = "/x" }]
+                "#,
+            )
+            .unwrap();
+            test_route(&config, None, "/x/y", Some("a"));
+            test_route(&config, None, "/x", Some("c"));
+        }
+    }
+
+    #[test]
+    fn path_routes() {
+        let config = routes();
+        test_route(&config, None, "/cats", Some("cats"));
+        test_route(&config, None, "/cats/a", Some("cats"));
+        test_route(&config, Some("other.org"), "/cats/a", Some("cats"));
+        test_route(&config, None, "/catsup", None);
+        test_route(&config, None, "/", None);
+    }
+
+    #[test]
+    fn host_routes() {
+        let config = routes();
+        test_route(&config, Some("app.example.com"), "/", Some("app"));
+        test_route(&config, Some("app.example.com"), "/cats", Some("app"));
+        test_route(&config, Some("app.example.com"), "/api/x", Some("app-api"));
+        test_route(&config, Some("app.example.com"), "/apis", Some("app"));
+        test_route(&config, Some("a.example.com"), "/x", Some("tenants"));
+        test_route(&config, Some("a.b.example.com"), "/x", None);
+        test_route(&config, Some("a.b.example.com"), "/cats", Some("cats"));
+        test_route(&config, Some("a.eu.example.com"), "/x", Some("eu-tenants"));
+        test_route(&config, Some("example.com"), "/cats", Some("cats"));
+        test_route(&config, Some("example.com"), "/x", None);
+    }
+
+    #[test]
+    fn default_backend_catches_unrouted_requests() {
+        let config = config(
+            r#"
+            default_backend = "www"
+
+            [backends.cats]
+            url = "http://cats.internal"
+            scope = "cats:*"
+
+            [backends.www]
+            url = "http://www.internal"
+            scope = "www:*"
+            "#,
+        )
+        .unwrap();
+        test_upstream(&config, "/", Some(("www", "/")));
+        test_upstream(&config, "/dogs/a", Some(("www", "/dogs/a")));
+        test_upstream(&config, "/www/a", Some(("www", "/a")));
+        test_upstream(&config, "/cats/a", Some(("cats", "/a")));
+    }
+
+    #[test]
+    fn invalid_default_backends_are_rejected() {
+        let err = config(
+            r#"
+            default_backend = "dogs"
+
+            [backends.cats]
+            url = "http://cats.internal"
+            scope = "cats:*"
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.contains("default_backend dogs is not a backend"),
+            "{}",
+            err
+        );
+
+        let err = config(
+            r#"
+            default_backend = "cats"
+
+            [backends.cats]
+            url = "http://cats.internal"
+            scope = "cats:*"
+
+            [backends.www]
+            url = "http://www.internal"
+            scope = "www:*"
+            prefix = "/"
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.contains("default_backend cats is never used, as backend www is served under /"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn conflicting_routes_are_rejected() {
+        let err = config(
+            r#"
+            [backends.a]
+            url = "http://a.internal"
+            scope = "a:*"
+            hosts = ["a.example.com", "b.example.com"]
+
+            [backends.b]
+            url = "http://b.internal"
+            scope = "b:*"
+            hosts = ["B.example.com"]
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.contains("Backends a and b are served twice under / on b.example.com"),
+            "{}",
+            err
+        );
+
+        let err = config(
+            r#"
+            [backends.a]
+            url = "http://a.internal"
+            scope = "a:*"
+
+            [backends.b]
+            url = "http://b.internal"
+            scope = "b:*"
+            prefix = "/a"
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.contains("Backends a and b are served twice under /a on every host"),
+            "{}",
+            err
+        );
+
+        let err = config(
+            r#"
+            [backends.a]
+            url = "http://a.internal"
+            scope = "a:*"
+            routes = [{ regex = "/a/.*" }, { prefix = "/b" }, { regex = "/a/.*" }]
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.contains("Backend a is served twice under regex /a/.* on every host"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn invalid_routes_are_rejected() {
+        for backend in &[
+            r#"hosts = ["*.*.example.com"]"#,
+            r#"hosts = ["example..com"]"#,
+            r#"hosts = ["example.com:8080"]"#,
+            r#"prefix = "a""#,
+            r#"prefix = "/a/""#,
+            r#"prefix = "/a//b""#,
+            r#"prefix = "/a?b""#,
+            r#"prefix = "/a"
+            routes = [{ prefix = "/b" }]"#,
+            r#"routes = [{ prefix = "/a", regex = "/a" }]"#,
+            r#"routes = [{ rewrite = "/a" }]"#,
+            r#"routes = [{ regex = "/a(" }]"#,
+            r#"routes = [{ regex = "/a", rewrite = "$1" }]"#,
+            r#"routes = [{ prefix = "/a", rewrite = "a" }]"#,
+        ] {
+            let result = config(&format!(
+                r#"
+                [backends.a]
+                url = "http://a.internal"
+                scope = "a:*"
+                {}
+                "#,
+                backend
+            ));
+            assert!(result.is_err(), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(strip_prefix("/a", "/a"), Some(""));
+        assert_eq!(strip_prefix("/a/b", "/a"), Some("/b"));
+        assert_eq!(strip_prefix("/a?b", "/a"), Some("?b"));
+        assert_eq!(strip_prefix("/ab", "/a"), None);
+        assert_eq!(strip_prefix("/ab", ""), Some("/ab"));
+        assert_eq!(join("", ""), "/");
+        assert_eq!(join("/", "?x"), "/?x");
+        assert_eq!(join("/v2/", "/x"), "/v2/x");
+    }
+
+    #[test]
+    fn host_patterns() {
+        let wildcard = HostPattern::parse("*.Example.com").unwrap();
+        assert_eq!(wildcard, HostPattern::Subdomain("example.com".to_string()));
+        assert!(wildcard.matches(Some("a.example.com")));
+        assert!(!wildcard.matches(Some("a.b.example.com")));
+        assert!(!wildcard.matches(Some("example.com")));
+        assert!(!wildcard.matches(Some("aexample.com")));
+        assert!(!wildcard.matches(None));
+        let exact = HostPattern::parse("Example.com").unwrap();
+        assert!(exact.matches(Some("example.com")));
+        assert!(!exact.matches(Some("a.example.com")));
+        for invalid in &["*", "*.", "a..b", "a.*.b", "a b", ""] {
+            assert_eq!(HostPattern::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn public_paths() {
+        let route = RouteConfig {
+            rewrite: Some("/v2".to_string()),
+            ..RouteConfig::with_prefix("/api/v2/billing")
+        };
+        assert_eq!(
+            route.public_path("/v2/x?y"),
+            Some("/api/v2/billing/x?y".to_string())
+        );
+        assert_eq!(
+            route.public_path("/v2"),
+            Some("/api/v2/billing".to_string())
+        );
+        assert_eq!(route.public_path("/v3"), None);
+        assert_eq!(
+            RouteConfig::with_prefix("/").public_path("?x"),
+            Some("/?x".to_string())
+        );
+    }
+}
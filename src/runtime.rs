@@ -0,0 +1,80 @@
+use crate::auth::token::TokenAuthenticator;
+use crate::auth::FrontendAuthType;
+use crate::config::{Backend, Config};
+use crate::tls::server::ServerTls;
+use std::error::Error;
+
+/// Server state shared by every connection and request.
+///
+/// Everything that can be prepared ahead of time, such as the token
+/// decoding key, the listener certificate and the backend clients, is
+/// prepared when the runtime is created, so that handling a request only
+/// borrows from it.
+pub struct Runtime {
+    pub config: Config,
+    /// Only present if some backend uses token authentication, so that the
+    /// key file is not needed otherwise.
+    pub token_authenticator: Option<TokenAuthenticator>,
+    pub tls: Option<ServerTls>,
+}
+
+impl Runtime {
+    pub fn new(config: Config) -> Result<Self, Box<dyn Error>> {
+        let uses_tokens = config
+            .backends
+            .values()
+            .any(|backend| matches!(backend.frontend_auth, FrontendAuthType::Token));
+        let token_authenticator = match uses_tokens {
+            true => Some(TokenAuthenticator::new(&config.auth)?),
+            false => None,
+        };
+        let tls = match &config.tls {
+            Some(tls) => Some(
+                ServerTls::new(tls)
+                    .map_err(|e| format!("Unable to load TLS certificate: {}", e))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            config,
+            token_authenticator,
+            tls,
+        })
+    }
+
+    /// Returns the name and backend serving a request for `path` on `host`,
+    /// and the path to pass to the backend.
+    pub fn route(&self, host: Option<&str>, path: &str) -> Option<(&String, &Backend, String)> {
+        let (name, upstream_path) = self.config.routes.find(host, path)?;
+        let (name, backend) = self.config.backends.get_key_value(name)?;
+        Some((name, backend, upstream_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Runtime;
+    use crate::config::tests::config;
+
+    fn runtime(frontend_auth: &str) -> Result<Runtime, String> {
+        let mut config = config(&format!(
+            r#"
+            [backends.open]
+            url = "http://open.internal"
+            scope = "open:*"
+            frontend_auth = "{}"
+            "#,
+            frontend_auth
+        ))
+        .unwrap();
+        config.auth.keyfile = "missing.pem".to_string();
+        Runtime::new(config).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn key_is_only_loaded_for_token_backends() {
+        assert!(runtime("NoAuth").unwrap().token_authenticator.is_none());
+        assert!(runtime("Token").is_err());
+    }
+}
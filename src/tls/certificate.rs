@@ -0,0 +1,245 @@
+use rustls::sign::{self, CertifiedKey, SigningKey};
+use rustls::SignatureScheme;
+use rustls_pemfile::{read_all, Item};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+fn load_certs(path: &str) -> Result<Vec<rustls::Certificate>, Box<dyn Error>> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let certs: Vec<rustls::Certificate> = read_all(&mut reader)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(rustls::Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", path).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<rustls::PrivateKey, Box<dyn Error>> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let mut keys = read_all(&mut reader)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) => Some(rustls::PrivateKey(der)),
+            _ => None,
+        });
+    match (keys.next(), keys.next()) {
+        (Some(key), None) => Ok(key),
+        (None, _) => Err(format!("No private key in {}", path).into()),
+        (Some(_), Some(_)) => Err(format!("Multiple private keys in {}", path).into()),
+    }
+}
+
+/// Checks that a private key belongs to a certificate, by verifying a
+/// signature made with the key against the certificate.
+fn check_key_matches(cert: &rustls::Certificate, key: &dyn SigningKey) -> Result<(), String> {
+    const MESSAGE: &[u8] = b"demogorgon certificate check";
+    let signer = key
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])
+        .ok_or("Unsupported private key type")?;
+    let algorithm = match signer.get_scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+    let signature = signer.sign(MESSAGE).map_err(|e| e.to_string())?;
+    let cert = webpki::EndEntityCert::from(&cert.0)
+        .map_err(|e| format!("Invalid certificate: {:?}", e))?;
+    cert.verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|_| "The certificate does not match the private key".to_string())
+}
+
+/// Returns the end of the validity period of a certificate.
+pub fn not_after(cert: &rustls::Certificate) -> Option<SystemTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let seconds = u64::try_from(cert.validity().not_after.timestamp()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Logs a warning if a certificate expires within `warning`.
+fn check_expiry(path: &str, cert: &rustls::Certificate, warning: Duration) {
+    let not_after = match not_after(cert) {
+        Some(not_after) => not_after,
+        None => {
+            log::warn!("Unable to read the expiry date of {}", path);
+            return;
+        }
+    };
+    match not_after.duration_since(SystemTime::now()) {
+        Ok(remaining) if remaining < warning => log::warn!(
+            "TLS certificate {} expires in {} days",
+            path,
+            remaining.as_secs() / 86_400
+        ),
+        Ok(_) => {}
+        Err(_) => log::error!("TLS certificate {} has expired", path),
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+struct Loaded {
+    key: CertifiedKey,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+/// A certificate and key, which are swapped when their files change.
+/// Connections that have completed their handshake are unaffected.
+pub struct CertificateStore {
+    pub cert: String,
+    key: String,
+    expiry_warning: Duration,
+    loaded: RwLock<Loaded>,
+}
+
+impl CertificateStore {
+    pub fn load(cert: &str, key: &str, expiry_warning: Duration) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            expiry_warning,
+            loaded: RwLock::new(Self::read(cert, key, expiry_warning)?),
+        })
+    }
+
+    fn read(cert: &str, key: &str, expiry_warning: Duration) -> Result<Loaded, Box<dyn Error>> {
+        let modified = (modified(cert), modified(key));
+        let certs = load_certs(cert)?;
+        let signing_key = sign::any_supported_type(&load_key(key)?)
+            .map_err(|_| format!("Unsupported private key type in {}", key))?;
+        check_key_matches(&certs[0], signing_key.as_ref())
+            .map_err(|e| format!("{} and {}: {}", cert, key, e))?;
+        check_expiry(cert, &certs[0], expiry_warning);
+        Ok(Loaded {
+            key: CertifiedKey::new(certs, Arc::new(signing_key)),
+            modified,
+        })
+    }
+
+    pub fn certified_key(&self) -> CertifiedKey {
+        self.loaded.read().unwrap().key.clone()
+    }
+
+    /// Reloads the certificate if either file has changed since it was
+    /// loaded. Returns true if it was reloaded.
+    pub fn reload_if_changed(&self) -> Result<bool, Box<dyn Error>> {
+        let modified = (modified(&self.cert), modified(&self.key));
+        if modified == self.loaded.read().unwrap().modified {
+            return Ok(false);
+        }
+        let loaded = Self::read(&self.cert, &self.key, self.expiry_warning);
+        let mut current = self.loaded.write().unwrap();
+        match loaded {
+            Ok(loaded) => {
+                *current = loaded;
+                Ok(true)
+            }
+            Err(err) => {
+                // Do not retry the same broken files on every check.
+                current.modified = modified;
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{load_certs, not_after, CertificateStore};
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use uuid::Uuid;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/src/tls/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn load(cert: &str, key: &str) -> Result<CertificateStore, String> {
+        CertificateStore::load(&testdata(cert), &testdata(key), Duration::from_secs(1))
+            .map_err(|e| e.to_string())
+    }
+
+    fn leaf(store: &CertificateStore) -> Vec<u8> {
+        store.certified_key().cert[0].0.clone()
+    }
+
+    /// Copies a certificate and key to `dir`, with the given modification
+    /// time.
+    fn install(dir: &Path, name: &str, modified: SystemTime) {
+        for ext in &["crt", "key"] {
+            let path = dir.join(format!("listener.{}", ext));
+            fs::copy(testdata(&format!("{}.{}", name, ext)), &path).unwrap();
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(modified).unwrap();
+        }
+    }
+
+    #[test]
+    fn matching_keys_are_loaded() {
+        assert!(load("one.crt", "one.key").is_ok());
+        assert!(load("two.crt", "two.key").is_ok());
+    }
+
+    #[test]
+    fn invalid_pairs_are_rejected() {
+        let mismatch = load("one.crt", "two.key").err().unwrap();
+        assert!(mismatch.contains("does not match"), "{}", mismatch);
+        assert!(load("one.crt", "one.crt").is_err());
+        assert!(load("one.key", "one.key").is_err());
+        assert!(load("none.crt", "one.key").is_err());
+    }
+
+    #[test]
+    fn expiry_dates() {
+        let cert = &load_certs(&testdata("one.crt")).unwrap()[0];
+        assert_eq!(
+            not_after(cert),
+            Some(UNIX_EPOCH + Duration::from_secs(4_945_960_068))
+        );
+        assert_eq!(
+            not_after(&rustls::Certificate(vec![0x30, 0x03, 0x02])),
+            None
+        );
+    }
+
+    #[test]
+    fn changed_certificates_are_reloaded() {
+        let dir = std::env::temp_dir().join(format!("demogorgon-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let (cert, key) = (dir.join("listener.crt"), dir.join("listener.key"));
+        let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+        let then = SystemTime::now() - Duration::from_secs(60);
+
+        install(&dir, "one", then);
+        let store = CertificateStore::load(cert, key, Duration::ZERO).unwrap();
+        let first = leaf(&store);
+        assert!(!store.reload_if_changed().unwrap());
+
+        install(&dir, "two", SystemTime::now());
+        assert!(store.reload_if_changed().unwrap());
+        assert_ne!(leaf(&store), first);
+
+        fs::write(cert, "not a certificate").unwrap();
+        assert!(store.reload_if_changed().is_err());
+        assert!(!store.reload_if_changed().unwrap());
+        assert_ne!(leaf(&store), first);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,6 +2,9 @@ use rustls_pemfile::{read_all, Item};
 use serde::Deserialize;
 use std::{error::Error, fs, io};
 
+pub mod certificate;
+pub mod server;
+
 #[derive(Clone, Deserialize, Debug)]
 pub enum ClientCertAuth {
     PEMFile(String), // DERFile{cert_chain: String, key_file: String}
@@ -28,10 +31,7 @@ impl ClientCertAuth {
                             if privkey.is_none() {
                                 privkey = Some(rustls::PrivateKey(a));
                             } else {
-                                return Err(Box::new(io::Error::new(
-                                    io::ErrorKind::Other,
-                                    "Multiple private keys.",
-                                )));
+                                return Err(Box::new(io::Error::other("Multiple private keys.")));
                             }
                         }
                     }
@@ -39,10 +39,7 @@ impl ClientCertAuth {
 
                 match privkey {
                     Some(a) => Ok((cert_chain, a)),
-                    None => Err(Box::new(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Missing private key.",
-                    ))),
+                    None => Err(Box::new(io::Error::other("Missing private key."))),
                 }
             }
         }
@@ -0,0 +1,439 @@
+use super::certificate::CertificateStore;
+use crate::routing::HostPattern;
+use crate::runtime::Runtime;
+use crate::service_handler;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use rustls::sign::CertifiedKey;
+use rustls::{
+    ClientHello, NoClientAuth, ProtocolVersion, ResolvesServerCert, ServerConfig, Session,
+    SupportedCipherSuite, ALL_CIPHERSUITES,
+};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// Time allowed for a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time to wait before accepting again after an error, as hyper does.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// TLS termination on the listener.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerTlsConfig {
+    /// PEM file with the certificate chain, leaf first. This certificate is
+    /// used when the client sends no server name, or one that none of
+    /// `certificates` is for.
+    pub cert: String,
+    /// PEM file with the private key.
+    pub key: String,
+
+    /// Further certificates, selected by the server name the client sends.
+    #[serde(default)]
+    pub certificates: Vec<SniCertificate>,
+
+    #[serde(default)]
+    pub min_version: TlsVersion,
+
+    /// Cipher suites to offer, by their IANA names. All suites supported by
+    /// rustls are offered if empty.
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+
+    /// Protocols offered with ALPN, most preferred first.
+    #[serde(default = "default_alpn")]
+    pub alpn: Vec<String>,
+
+    /// Seconds between checks of the certificate and key files for changes.
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: f64,
+
+    /// Days before a certificate expires to start warning about it.
+    #[serde(default = "default_expiry_warning")]
+    pub expiry_warning: u64,
+}
+
+/// A certificate for the listed hosts. A host of `*.example.com` matches
+/// any single label below `example.com`; an exact host takes precedence.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertificate {
+    pub hosts: Vec<String>,
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+fn default_alpn() -> Vec<String> {
+    vec!["h2".to_string(), "http/1.1".to_string()]
+}
+
+fn default_reload_interval() -> f64 {
+    60.0
+}
+
+fn default_expiry_warning() -> u64 {
+    30
+}
+
+impl ListenerTlsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.reload_interval.is_finite() || self.reload_interval <= 0.0 {
+            return Err("tls reload_interval must be a positive number".to_string());
+        }
+        if let Some(protocol) = self
+            .alpn
+            .iter()
+            .find(|p| p.as_str() != "h2" && p.as_str() != "http/1.1")
+        {
+            return Err(format!("tls alpn protocol {} is not supported", protocol));
+        }
+        let mut seen = HashSet::new();
+        for certificate in &self.certificates {
+            if certificate.hosts.is_empty() {
+                return Err(format!("tls certificate {} has no hosts", certificate.cert));
+            }
+            for host in &certificate.hosts {
+                if HostPattern::parse(host).is_none() {
+                    return Err(format!("tls host {} is not a valid host name", host));
+                }
+                if !seen.insert(host.to_ascii_lowercase()) {
+                    return Err(format!("tls host {} has more than one certificate", host));
+                }
+            }
+        }
+        let suites = self.cipher_suites()?;
+        let versions = self.versions();
+        if !suites
+            .iter()
+            .any(|s| versions.iter().any(|v| s.usable_for_version(*v)))
+        {
+            return Err("tls cipher_suites has no suite for the allowed versions".to_string());
+        }
+        Ok(())
+    }
+
+    fn versions(&self) -> Vec<ProtocolVersion> {
+        match self.min_version {
+            TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+        }
+    }
+
+    fn cipher_suites(&self) -> Result<Vec<&'static SupportedCipherSuite>, String> {
+        if self.cipher_suites.is_empty() {
+            return Ok(ALL_CIPHERSUITES.to_vec());
+        }
+        self.cipher_suites
+            .iter()
+            .map(|name| {
+                ALL_CIPHERSUITES
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| format!("tls cipher suite {} is not supported", name))
+            })
+            .collect()
+    }
+}
+
+/// Selects the certificate for a connection by the server name the client
+/// asked for, falling back to the default certificate.
+struct SniResolver {
+    default: Arc<CertificateStore>,
+    /// Host names, or `*.` followed by a domain, and their certificates.
+    hosts: Vec<(HostPattern, Arc<CertificateStore>)>,
+}
+
+impl SniResolver {
+    fn select(&self, server_name: Option<&str>) -> &CertificateStore {
+        let name = server_name.map(|name| name.to_ascii_lowercase());
+        self.hosts
+            .iter()
+            .filter(|(host, _)| host.matches(name.as_deref()))
+            .max_by_key(|(host, _)| host.specificity())
+            .map(|(_, store)| store.as_ref())
+            .unwrap_or(&self.default)
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let server_name = client_hello.server_name().map(|name| {
+            let name: &str = name.into();
+            name.to_string()
+        });
+        Some(self.select(server_name.as_deref()).certified_key())
+    }
+}
+
+/// The TLS state of the listener.
+pub struct ServerTls {
+    acceptor: TlsAcceptor,
+    certificates: Vec<Arc<CertificateStore>>,
+    reload_interval: Duration,
+}
+
+impl ServerTls {
+    pub fn new(config: &ListenerTlsConfig) -> Result<Self, Box<dyn Error>> {
+        let expiry_warning = Duration::from_secs(config.expiry_warning * 86_400);
+        let default = Arc::new(CertificateStore::load(
+            &config.cert,
+            &config.key,
+            expiry_warning,
+        )?);
+        let mut certificates = vec![default.clone()];
+        let mut hosts = Vec::new();
+        for certificate in &config.certificates {
+            let store = Arc::new(CertificateStore::load(
+                &certificate.cert,
+                &certificate.key,
+                expiry_warning,
+            )?);
+            for host in &certificate.hosts {
+                let host = HostPattern::parse(host)
+                    .ok_or_else(|| format!("tls host {} is not a valid host name", host))?;
+                hosts.push((host, store.clone()));
+            }
+            certificates.push(store);
+        }
+
+        let mut server_config =
+            ServerConfig::with_ciphersuites(NoClientAuth::new(), &config.cipher_suites()?);
+        server_config.versions = config.versions();
+        server_config.ignore_client_order = !config.cipher_suites.is_empty();
+        server_config.set_protocols(
+            &config
+                .alpn
+                .iter()
+                .map(|p| p.as_bytes().to_vec())
+                .collect::<Vec<_>>(),
+        );
+        server_config.cert_resolver = Arc::new(SniResolver { default, hosts });
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            certificates,
+            reload_interval: Duration::from_secs_f64(config.reload_interval),
+        })
+    }
+
+    /// Starts watching the certificate files for changes.
+    pub fn spawn_reload(&self) {
+        let certificates = self.certificates.clone();
+        let mut interval = tokio::time::interval(self.reload_interval);
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                for store in &certificates {
+                    match store.reload_if_changed() {
+                        Ok(true) => log::info!("Reloaded TLS certificate {}", store.cert),
+                        Ok(false) => {}
+                        Err(err) => log::error!(
+                            "Unable to reload TLS certificate {}, keeping the previous one: {}",
+                            store.cert,
+                            err
+                        ),
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Returns true for errors that only affect the connection being accepted.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Accepts TLS connections and serves each with HTTP/2 or HTTP/1.1,
+/// as negotiated with ALPN.
+pub async fn serve(
+    address: SocketAddr,
+    tls: &ServerTls,
+    runtime: Arc<Runtime>,
+    h2c: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) if is_connection_error(&err) => continue,
+            Err(err) => {
+                // Errors such as running out of file descriptors persist, so
+                // retrying at once would spin
+                log::warn!("Unable to accept connection: {}", err);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let acceptor = tls.acceptor.clone();
+        let runtime = runtime.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        log::debug!("TLS handshake with {} failed: {}", remote_addr, err);
+                        return;
+                    }
+                    Err(_) => {
+                        log::debug!("TLS handshake with {} timed out", remote_addr);
+                        return;
+                    }
+                };
+            let h2 = stream.get_ref().1.get_alpn_protocol() == Some(b"h2");
+            let mut http = Http::new();
+            if h2 {
+                http.http2_only(true);
+            } else {
+                http.http1_only(!h2c);
+            }
+            let remote_addr = remote_addr.ip();
+            let service = service_fn(move |req| service_handler(req, remote_addr, runtime.clone()));
+            if let Err(err) = http.serve_connection(stream, service).with_upgrades().await {
+                log::debug!("Connection from {} closed: {}", remote_addr, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{ListenerTlsConfig, ServerTls, SniCertificate, SniResolver, TlsVersion};
+    use crate::routing::HostPattern;
+    use crate::tls::certificate::CertificateStore;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/src/tls/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn config(certificates: &[(&str, &[&str])]) -> ListenerTlsConfig {
+        ListenerTlsConfig {
+            cert: testdata("one.crt"),
+            key: testdata("one.key"),
+            certificates: certificates
+                .iter()
+                .map(|(name, hosts)| SniCertificate {
+                    hosts: hosts.iter().map(|h| h.to_string()).collect(),
+                    cert: testdata(&format!("{}.crt", name)),
+                    key: testdata(&format!("{}.key", name)),
+                })
+                .collect(),
+            min_version: TlsVersion::Tls12,
+            cipher_suites: vec![],
+            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            reload_interval: 60.0,
+            expiry_warning: 30,
+        }
+    }
+
+    fn store(name: &str) -> Arc<CertificateStore> {
+        Arc::new(
+            CertificateStore::load(
+                &testdata(&format!("{}.crt", name)),
+                &testdata(&format!("{}.key", name)),
+                Duration::ZERO,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn test_select(resolver: &SniResolver, server_name: Option<&str>, expected: &str) {
+        assert_eq!(
+            resolver.select(server_name).cert,
+            testdata(expected),
+            "{:?}",
+            server_name
+        );
+    }
+
+    #[test]
+    fn certificates_are_selected_by_server_name() {
+        let (one, two) = (store("one"), store("two"));
+        let resolver = SniResolver {
+            default: one.clone(),
+            hosts: vec![
+                (HostPattern::parse("*.two.example").unwrap(), two.clone()),
+                (
+                    HostPattern::parse("exact.two.example").unwrap(),
+                    one.clone(),
+                ),
+                (HostPattern::parse("two.example").unwrap(), two),
+            ],
+        };
+        test_select(&resolver, None, "one.crt");
+        test_select(&resolver, Some("two.example"), "two.crt");
+        test_select(&resolver, Some("TWO.example"), "two.crt");
+        test_select(&resolver, Some("a.two.example"), "two.crt");
+        test_select(&resolver, Some("exact.two.example"), "one.crt");
+        test_select(&resolver, Some("a.b.two.example"), "one.crt");
+        test_select(&resolver, Some("other.example"), "one.crt");
+    }
+
+    #[test]
+    fn sni_certificates_are_loaded() {
+        let config = config(&[("two", &["two.example", "*.two.example"])]);
+        assert!(config.validate().is_ok());
+        let tls = ServerTls::new(&config).unwrap();
+        assert_eq!(tls.certificates.len(), 2);
+    }
+
+    #[test]
+    fn valid_configs() {
+        assert!(config(&[]).validate().is_ok());
+        let config = ListenerTlsConfig {
+            min_version: TlsVersion::Tls13,
+            cipher_suites: vec!["TLS13_AES_128_GCM_SHA256".to_string()],
+            ..config(&[])
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let unknown_suite = ListenerTlsConfig {
+            cipher_suites: vec!["TLS_RSA_WITH_RC4_128_MD5".to_string()],
+            ..config(&[])
+        };
+        assert!(unknown_suite.validate().is_err());
+        let no_usable_suite = ListenerTlsConfig {
+            min_version: TlsVersion::Tls13,
+            cipher_suites: vec!["TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string()],
+            ..config(&[])
+        };
+        assert!(no_usable_suite.validate().is_err());
+        let unknown_alpn = ListenerTlsConfig {
+            alpn: vec!["spdy/3".to_string()],
+            ..config(&[])
+        };
+        assert!(unknown_alpn.validate().is_err());
+        assert!(config(&[("two", &[])]).validate().is_err());
+        assert!(config(&[("two", &["*.*.example"])]).validate().is_err());
+        assert!(config(&[("two", &["a..example"])]).validate().is_err());
+        assert!(config(&[("two", &["a.example"]), ("one", &["A.example"])])
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use hyper::header::{HeaderMap, HeaderValue, CONNECTION, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// Protocol upgrades, such as WebSockets, to a backend.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UpgradeConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds an upgraded connection may carry no data in either direction
+    /// before it is closed.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: f64,
+}
+
+fn default_idle_timeout() -> f64 {
+    300.0
+}
+
+impl Default for UpgradeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_timeout: default_idle_timeout(),
+        }
+    }
+}
+
+impl UpgradeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.idle_timeout.is_finite() || self.idle_timeout <= 0.0 {
+            return Err("upgrade idle_timeout must be a positive number".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the protocol a request asks to upgrade the connection to.
+pub fn requested_protocol<B>(req: &Request<B>) -> Option<HeaderValue> {
+    let upgrade = req
+        .headers()
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    if !upgrade {
+        return None;
+    }
+    req.headers().get(UPGRADE).cloned()
+}
+
+/// Restores the hop-by-hop headers of an upgrade, which are removed along
+/// with the other hop-by-hop headers when a message is proxied.
+pub fn set_upgrade_headers(headers: &mut HeaderMap<HeaderValue>, protocol: HeaderValue) {
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(UPGRADE, protocol);
+}
+
+/// Completes an upgrade that the backend has accepted.
+///
+/// Once the `101 Switching Protocols` response has reached the client, the
+/// client and backend connections are spliced together until either side
+/// closes or the connection goes idle. Any other response is returned as is.
+pub fn proxy_upgrade(
+    mut response: Response<Body>,
+    client: OnUpgrade,
+    protocol: HeaderValue,
+    name: &str,
+    config: &UpgradeConfig,
+) -> Response<Body> {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return response;
+    }
+    let upstream = hyper::upgrade::on(&mut response);
+    set_upgrade_headers(response.headers_mut(), protocol);
+
+    let name = name.to_string();
+    let idle_timeout = Duration::from_secs_f64(config.idle_timeout);
+    tokio::spawn(async move {
+        let (client, upstream) = match tokio::try_join!(client, upstream) {
+            Ok(connections) => connections,
+            Err(err) => {
+                log::warn!("Upgrade to backend {} failed: {}", name, err);
+                return;
+            }
+        };
+        match splice(client, upstream, idle_timeout).await {
+            Ok((sent, received)) => log::debug!(
+                "Upgraded connection to backend {} closed after sending {} and receiving {} bytes",
+                name,
+                sent,
+                received
+            ),
+            Err(err) => log::info!("Upgraded connection to backend {} closed: {}", name, err),
+        }
+    });
+    response
+}
+
+/// A connection that records when it last carried data in either direction.
+struct Tracked<T> {
+    inner: T,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl<T> Tracked<T> {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.touch();
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                self.touch();
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Waits until no data has been carried for `idle_timeout`.
+async fn idle(last_active: &Mutex<Instant>, idle_timeout: Duration) {
+    loop {
+        let deadline = *last_active.lock().unwrap() + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// Copies data between two connections in both directions until both have
+/// closed, or neither has carried data for `idle_timeout`. Each direction is
+/// copied independently, so a peer that is slow to read only holds up the
+/// data sent to it.
+///
+/// Returns the number of bytes sent from the client to the upstream, and
+/// received from the upstream by the client.
+async fn splice<C, U>(client: C, upstream: U, idle_timeout: Duration) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    let mut client = Tracked {
+        inner: client,
+        last_active: last_active.clone(),
+    };
+    let mut upstream = Tracked {
+        inner: upstream,
+        last_active: last_active.clone(),
+    };
+    tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut client, &mut upstream) => result,
+        _ = idle(&last_active, idle_timeout) => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{requested_protocol, splice};
+    use hyper::Request;
+    use std::io;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn protocol(connection: &str, upgrade: Option<&str>) -> Option<String> {
+        let mut req = Request::get("/").header("connection", connection);
+        if let Some(upgrade) = upgrade {
+            req = req.header("upgrade", upgrade);
+        }
+        requested_protocol(&req.body(()).unwrap()).map(|p| p.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn upgrade_requests() {
+        assert_eq!(
+            protocol("Upgrade", Some("websocket")),
+            Some("websocket".to_string())
+        );
+        assert_eq!(
+            protocol("keep-alive, upgrade", Some("websocket")),
+            Some("websocket".to_string())
+        );
+        assert_eq!(protocol("keep-alive", Some("websocket")), None);
+        assert_eq!(protocol("upgrade", None), None);
+    }
+
+    #[tokio::test]
+    async fn splice_copies_both_ways() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (upstream, mut upstream_peer) = tokio::io::duplex(64);
+        let splice = tokio::spawn(splice(client, upstream, Duration::from_secs(5)));
+
+        client_peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        upstream_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        upstream_peer.write_all(b"pong!").await.unwrap();
+        let mut buf = [0; 5];
+        client_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong!");
+
+        drop(client_peer);
+        drop(upstream_peer);
+        assert_eq!(splice.await.unwrap().unwrap(), (4, 5));
+    }
+
+    #[tokio::test]
+    async fn splice_closes_when_idle() {
+        let (client, _client_peer) = tokio::io::duplex(64);
+        let (upstream, _upstream_peer) = tokio::io::duplex(64);
+        let result = splice(client, upstream, Duration::from_millis(50)).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn slow_reader_does_not_stall_the_other_direction() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (upstream, upstream_peer) = tokio::io::duplex(64);
+        tokio::spawn(splice(client, upstream, Duration::from_secs(5)));
+
+        // The client does not read what the upstream sends
+        let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream_peer);
+        tokio::spawn(async move { upstream_write.write_all(&[0; 4096]).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        client_peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        tokio::time::timeout(Duration::from_secs(1), upstream_read.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn activity_keeps_the_connection_open() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (upstream, mut upstream_peer) = tokio::io::duplex(64);
+        let splice = tokio::spawn(splice(client, upstream, Duration::from_millis(100)));
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client_peer.write_all(b"x").await.unwrap();
+            let mut buf = [0; 1];
+            upstream_peer.read_exact(&mut buf).await.unwrap();
+        }
+        drop(client_peer);
+        drop(upstream_peer);
+        assert_eq!(splice.await.unwrap().unwrap(), (5, 0));
+    }
+}
@@ -0,0 +1,471 @@
+use super::health::Health;
+use super::{send, TimeoutConfig, UpstreamError, UpstreamProtocol};
+use crate::config::{HttpsClient, PoolConfig};
+use crate::proxy::target_upstream;
+use crate::tls::ClientCertAuth;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response, Uri, Version};
+use serde::Deserialize;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How requests are spread over the upstreams of a backend.
+#[derive(Clone, Default, Deserialize, Debug)]
+pub enum BalanceStrategy {
+    /// Each upstream in turn.
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in flight.
+    LeastOutstanding,
+    /// Each upstream in turn, in proportion to its weight.
+    Weighted,
+}
+
+/// One target of a backend, as configured.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub url: String,
+    cert_auth: Option<ClientCertAuth>,
+
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl UpstreamConfig {
+    pub fn new(url: String, cert_auth: Option<ClientCertAuth>) -> Self {
+        Self {
+            url,
+            cert_auth,
+            weight: default_weight(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let uri: Uri = self
+            .url
+            .parse()
+            .map_err(|e| format!("upstream {} is not a valid URL: {}", self.url, e))?;
+        if uri.scheme().is_none() || uri.authority().is_none() {
+            return Err(format!("upstream {} must be an absolute URL", self.url));
+        }
+        if self.weight == 0 {
+            return Err(format!("upstream {} weight must be at least 1", self.url));
+        }
+        Ok(())
+    }
+}
+
+/// One target of a backend, with its own client and connection pool.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub url: String,
+    pub weight: u32,
+    client: HttpsClient,
+    protocol: UpstreamProtocol,
+    outstanding: Arc<AtomicUsize>,
+    health: Arc<Health>,
+}
+
+impl Upstream {
+    /// Builds an upstream and the client shared by every request to it.
+    pub fn new(
+        config: UpstreamConfig,
+        root_store: &rustls::RootCertStore,
+        pool: &PoolConfig,
+        timeouts: &TimeoutConfig,
+        protocol: UpstreamProtocol,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(timeouts.connect());
+        let mut tls = rustls::ClientConfig::new();
+        tls.root_store = root_store.clone();
+        tls.alpn_protocols = protocol.alpn_protocols();
+
+        if let Some(ca) = &config.cert_auth {
+            log::debug!("Creating HTTPS client for {} with Cert Auth", config.url);
+            let (cert_chain, privkey) = ca.get_client_cert()?;
+            tls.set_single_client_cert(cert_chain, privkey)?;
+        } else {
+            log::debug!("Creating HTTPS client for {}", config.url);
+        }
+
+        let https = hyper_rustls::HttpsConnector::from((http, tls));
+        let mut builder = Client::builder();
+        builder.http2_only(protocol == UpstreamProtocol::Http2);
+        if let Some(idle_timeout) = pool.idle_timeout {
+            builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        if let Some(max_idle) = pool.max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle);
+        }
+        Ok(Self {
+            url: config.url,
+            weight: config.weight,
+            client: builder.build(https),
+            protocol,
+            outstanding: Arc::default(),
+            health: Arc::default(),
+        })
+    }
+
+    /// Returns the pooled client, which is shared by every request to the
+    /// upstream.
+    pub fn client(&self) -> &HttpsClient {
+        &self.client
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Returns the number of requests to the upstream that are waiting for
+    /// response headers.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    /// Sends a request to this upstream, counting it as in flight until the
+    /// response headers arrive.
+    pub async fn send(
+        &self,
+        mut req: Request<Body>,
+        timeouts: &TimeoutConfig,
+    ) -> Result<Response<Body>, UpstreamError> {
+        target_upstream(&mut req, &self.url).map_err(UpstreamError::Target)?;
+        // The client's HTTP version says nothing about the upstream
+        // connection, and HTTP/2 requests cannot be sent over HTTP/1.
+        if req.version() == Version::HTTP_2 && self.protocol != UpstreamProtocol::Http2 {
+            *req.version_mut() = Version::HTTP_11;
+        }
+        let _outstanding = OutstandingGuard::new(&self.outstanding);
+        send(self.client(), req, timeouts).await
+    }
+}
+
+/// Counts a request as outstanding until it is dropped, which also covers
+/// requests cancelled before their response headers arrive.
+struct OutstandingGuard<'a>(&'a AtomicUsize);
+
+impl<'a> OutstandingGuard<'a> {
+    fn new(outstanding: &'a AtomicUsize) -> Self {
+        outstanding.fetch_add(1, Ordering::Relaxed);
+        Self(outstanding)
+    }
+}
+
+impl Drop for OutstandingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Picks the upstream for the next attempt of a request.
+///
+/// Upstreams out of rotation and those already `tried` are skipped. If that
+/// leaves none, untried upstreams out of rotation are used, and failing
+/// that any upstream, as sending to an unhealthy upstream is better than
+/// not sending at all.
+pub fn pick(
+    strategy: &BalanceStrategy,
+    upstreams: &[Upstream],
+    counter: &AtomicUsize,
+    tried: &[usize],
+) -> usize {
+    let untried: Vec<usize> = (0..upstreams.len())
+        .filter(|i| !tried.contains(i))
+        .collect();
+    let healthy: Vec<usize> = untried
+        .iter()
+        .copied()
+        .filter(|&i| upstreams[i].health.in_rotation())
+        .collect();
+    let candidates = if !healthy.is_empty() {
+        healthy
+    } else if !untried.is_empty() {
+        untried
+    } else {
+        (0..upstreams.len()).collect()
+    };
+    if candidates.len() == 1 {
+        return candidates[0];
+    }
+
+    let turn = counter.fetch_add(1, Ordering::Relaxed);
+    match strategy {
+        BalanceStrategy::RoundRobin => candidates[turn % candidates.len()],
+        BalanceStrategy::LeastOutstanding => {
+            // Start from a different candidate each time so that ties are
+            // spread evenly.
+            let start = turn % candidates.len();
+            let rotated = candidates[start..].iter().chain(&candidates[..start]);
+            *rotated
+                .min_by_key(|&&i| upstreams[i].outstanding.load(Ordering::Relaxed))
+                .unwrap()
+        }
+        BalanceStrategy::Weighted => {
+            let total: usize = candidates
+                .iter()
+                .map(|&i| upstreams[i].weight as usize)
+                .sum();
+            let mut slot = turn % total;
+            for &i in &candidates {
+                let weight = upstreams[i].weight as usize;
+                if slot < weight {
+                    return i;
+                }
+                slot -= weight;
+            }
+            unreachable!("slot is less than the total weight")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{pick, BalanceStrategy, Upstream, UpstreamConfig};
+    use crate::config::PoolConfig;
+    use crate::upstream::health::OutlierConfig;
+    use crate::upstream::{TimeoutConfig, UpstreamProtocol};
+    use hyper::body::HttpBody;
+    use hyper::header::HeaderValue;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, Version};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn outlier() -> OutlierConfig {
+        OutlierConfig {
+            consecutive_errors: 1,
+            ejection_time: 60.0,
+        }
+    }
+
+    fn upstream(url: String, timeouts: &TimeoutConfig, protocol: UpstreamProtocol) -> Upstream {
+        Upstream::new(
+            UpstreamConfig::new(url, None),
+            &rustls::RootCertStore::empty(),
+            &PoolConfig::default(),
+            timeouts,
+            protocol,
+        )
+        .unwrap()
+    }
+
+    fn upstreams(weights: &[u32]) -> Vec<Upstream> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| Upstream {
+                weight,
+                ..upstream(
+                    format!("http://10.0.0.{}", i),
+                    &TimeoutConfig::default(),
+                    UpstreamProtocol::default(),
+                )
+            })
+            .collect()
+    }
+
+    /// Starts a server that answers with the HTTP version of the request in
+    /// the body and a `grpc-status` trailer.
+    fn version_server(http2_only: bool) -> SocketAddr {
+        let service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    let version = format!("{:?}", req.version());
+                    sender.send_data(version.into()).await.unwrap();
+                    let mut trailers = hyper::HeaderMap::new();
+                    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                    sender.send_trailers(trailers).await.unwrap();
+                });
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(http2_only)
+            .serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Sends a request over `version` to an upstream spoken to over
+    /// `protocol`, returning the version the upstream saw and its trailers.
+    async fn round_trip(
+        protocol: UpstreamProtocol,
+        version: Version,
+        http2_only: bool,
+    ) -> (String, Option<hyper::HeaderMap>) {
+        let addr = version_server(http2_only);
+        let timeouts = TimeoutConfig {
+            total: Some(5.0),
+            ..TimeoutConfig::default()
+        };
+        let upstream = upstream(format!("http://{}", addr), &timeouts, protocol);
+        let req = Request::get("/test/")
+            .version(version)
+            .body(Body::empty())
+            .unwrap();
+        let mut body = upstream.send(req, &timeouts).await.unwrap().into_body();
+        let data = body.data().await.unwrap().unwrap();
+        let trailers = body.trailers().await.unwrap();
+        (String::from_utf8(data.to_vec()).unwrap(), trailers)
+    }
+
+    #[tokio::test]
+    async fn http2_upstream_with_prior_knowledge() {
+        let (version, trailers) = round_trip(UpstreamProtocol::Http2, Version::HTTP_11, true).await;
+        assert_eq!(version, "HTTP/2.0");
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+    }
+
+    #[tokio::test]
+    async fn http2_requests_to_http1_upstreams() {
+        let (version, _) = round_trip(UpstreamProtocol::Http1, Version::HTTP_2, false).await;
+        assert_eq!(version, "HTTP/1.1");
+        let (version, _) = round_trip(UpstreamProtocol::Auto, Version::HTTP_2, false).await;
+        assert_eq!(version, "HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_are_not_outstanding() {
+        // An upstream that accepts connections and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let timeouts = TimeoutConfig::default();
+        let upstream = upstream(
+            format!("http://{}", addr),
+            &timeouts,
+            UpstreamProtocol::Http1,
+        );
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        let mut send = Box::pin(upstream.send(req, &timeouts));
+        let waited = tokio::time::timeout(Duration::from_millis(100), &mut send).await;
+        assert!(waited.is_err());
+        assert_eq!(upstream.outstanding(), 1);
+        drop(send);
+        assert_eq!(upstream.outstanding(), 0);
+    }
+
+    fn picks(strategy: BalanceStrategy, upstreams: &[Upstream], n: usize) -> Vec<usize> {
+        let counter = AtomicUsize::new(0);
+        (0..n)
+            .map(|_| pick(&strategy, upstreams, &counter, &[]))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let upstreams = upstreams(&[1, 1, 1]);
+        assert_eq!(
+            picks(BalanceStrategy::RoundRobin, &upstreams, 6),
+            vec![0, 1, 2, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn weighted() {
+        let upstreams = upstreams(&[3, 1]);
+        assert_eq!(
+            picks(BalanceStrategy::Weighted, &upstreams, 8),
+            vec![0, 0, 0, 1, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn least_outstanding() {
+        let upstreams = upstreams(&[1, 1, 1]);
+        upstreams[0].outstanding.store(2, Ordering::Relaxed);
+        upstreams[2].outstanding.store(1, Ordering::Relaxed);
+        assert_eq!(
+            picks(BalanceStrategy::LeastOutstanding, &upstreams, 3),
+            vec![1, 1, 1]
+        );
+        upstreams[1].outstanding.store(1, Ordering::Relaxed);
+        assert_eq!(
+            picks(BalanceStrategy::LeastOutstanding, &upstreams, 4),
+            vec![1, 1, 2, 1]
+        );
+    }
+
+    #[test]
+    fn tried_upstreams_are_skipped() {
+        let upstreams = upstreams(&[1, 1, 1]);
+        let counter = AtomicUsize::new(0);
+        for strategy in &[
+            BalanceStrategy::RoundRobin,
+            BalanceStrategy::LeastOutstanding,
+            BalanceStrategy::Weighted,
+        ] {
+            for _ in 0..5 {
+                let i = pick(strategy, &upstreams, &counter, &[0, 2]);
+                assert_eq!(i, 1);
+                let i = pick(strategy, &upstreams, &counter, &[0]);
+                assert_ne!(i, 0);
+            }
+        }
+        // Once every upstream has been tried, any can be picked again.
+        let i = pick(
+            &BalanceStrategy::RoundRobin,
+            &upstreams,
+            &counter,
+            &[0, 1, 2],
+        );
+        assert!(i < 3);
+    }
+
+    #[test]
+    fn unhealthy_upstreams_are_skipped() {
+        let upstreams = upstreams(&[1, 1, 1]);
+        upstreams[1]
+            .health
+            .record("http://10.0.0.1", true, Some(&outlier()));
+        assert_eq!(
+            picks(BalanceStrategy::RoundRobin, &upstreams, 4),
+            vec![0, 2, 0, 2]
+        );
+        let counter = AtomicUsize::new(0);
+        assert_eq!(
+            pick(&BalanceStrategy::RoundRobin, &upstreams, &counter, &[0, 2]),
+            1
+        );
+    }
+
+    #[test]
+    fn invalid_upstreams_are_rejected() {
+        assert!(
+            UpstreamConfig::new("http://10.0.0.1/base".to_string(), None)
+                .validate()
+                .is_ok()
+        );
+        assert!(UpstreamConfig::new("/base".to_string(), None)
+            .validate()
+            .is_err());
+        assert!(UpstreamConfig::new("not a url".to_string(), None)
+            .validate()
+            .is_err());
+        let upstream = UpstreamConfig {
+            weight: 0,
+            ..UpstreamConfig::new("http://10.0.0.1".to_string(), None)
+        };
+        assert!(upstream.validate().is_err());
+    }
+}
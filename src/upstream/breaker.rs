@@ -0,0 +1,348 @@
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When the circuit breaker of a backend trips, and how it recovers.
+///
+/// The breaker opens after `consecutive_failures` failed requests in a row,
+/// or when at least `error_rate` of the requests in a `window` fail, once
+/// there have been `min_requests` in it. While open, requests fail fast.
+/// After `open_time` the breaker lets `half_open_requests` trial requests
+/// through, and closes again if they all succeed.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    pub error_rate: Option<f64>,
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u32,
+    /// Seconds over which the error rate is measured.
+    #[serde(default = "default_window")]
+    pub window: f64,
+    /// Seconds the breaker stays open for.
+    #[serde(default = "default_open_time")]
+    pub open_time: f64,
+    #[serde(default = "default_half_open_requests")]
+    pub half_open_requests: u32,
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_min_requests() -> u32 {
+    20
+}
+
+fn default_window() -> f64 {
+    10.0
+}
+
+fn default_open_time() -> f64 {
+    30.0
+}
+
+fn default_half_open_requests() -> u32 {
+    1
+}
+
+impl CircuitBreakerConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(rate) = self.error_rate {
+            if !(rate > 0.0 && rate <= 1.0) {
+                return Err("circuit_breaker error_rate must be above 0 and at most 1".to_string());
+            }
+        }
+        for (name, value) in &[("window", self.window), ("open_time", self.open_time)] {
+            if !value.is_finite() || *value <= 0.0 {
+                return Err(format!(
+                    "circuit_breaker {} must be a positive number",
+                    name
+                ));
+            }
+        }
+        if self.consecutive_failures == 0 || self.half_open_requests == 0 {
+            return Err(
+                "circuit_breaker consecutive_failures and half_open_requests must be at least 1"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: u32, successes: u32 },
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: State,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+}
+
+/// The circuit breaker of a backend. Clones share the same state.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    state: Arc<Mutex<BreakerState>>,
+}
+
+/// Permission to send a request while the breaker is closed or half-open.
+///
+/// The outcome of the request must be passed to [`Permit::record`]. A permit
+/// dropped without an outcome, such as when the client goes away, releases
+/// its trial slot without counting for or against the backend.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            config: config.clone(),
+            state: Arc::new(Mutex::new(BreakerState {
+                state: State::Closed,
+                consecutive_failures: 0,
+                window_start: Instant::now(),
+                window_requests: 0,
+                window_failures: 0,
+            })),
+        }
+    }
+
+    /// Returns a permit to send a request, or how long the client should
+    /// wait before trying again.
+    pub fn acquire(&self) -> Result<Permit<'_>, Duration> {
+        let mut state = self.state.lock().unwrap();
+        if let State::Open { until } = state.state {
+            let now = Instant::now();
+            if now < until {
+                return Err(until - now);
+            }
+            log::info!("Circuit breaker for backend {} is half-open", self.name);
+            state.state = State::HalfOpen {
+                in_flight: 0,
+                successes: 0,
+            };
+        }
+        match &mut state.state {
+            State::HalfOpen { in_flight, .. } => {
+                if *in_flight + 1 > self.config.half_open_requests {
+                    return Err(Duration::from_secs(1));
+                }
+                *in_flight += 1;
+                Ok(Permit {
+                    breaker: self,
+                    trial: true,
+                })
+            }
+            _ => Ok(Permit {
+                breaker: self,
+                trial: false,
+            }),
+        }
+    }
+
+    fn open(&self, state: &mut BreakerState, reason: &str) {
+        log::warn!(
+            "Circuit breaker for backend {} opened for {}s: {}",
+            self.name,
+            self.config.open_time,
+            reason
+        );
+        state.state = State::Open {
+            until: Instant::now() + Duration::from_secs_f64(self.config.open_time),
+        };
+    }
+
+    fn record(&self, trial: bool, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        match state.state {
+            State::Closed => {
+                let now = Instant::now();
+                if now - state.window_start >= Duration::from_secs_f64(self.config.window) {
+                    state.window_start = now;
+                    state.window_requests = 0;
+                    state.window_failures = 0;
+                }
+                state.window_requests += 1;
+                if !failed {
+                    state.consecutive_failures = 0;
+                    return;
+                }
+                state.window_failures += 1;
+                state.consecutive_failures += 1;
+
+                let rate = f64::from(state.window_failures) / f64::from(state.window_requests);
+                if state.consecutive_failures >= self.config.consecutive_failures {
+                    let reason = format!("{} consecutive failures", state.consecutive_failures);
+                    self.open(&mut state, &reason);
+                } else if self.config.error_rate.is_some_and(|limit| {
+                    state.window_requests >= self.config.min_requests && rate >= limit
+                }) {
+                    let reason = format!("error rate {:.0}%", rate * 100.0);
+                    self.open(&mut state, &reason);
+                }
+            }
+            State::HalfOpen {
+                ref mut in_flight,
+                ref mut successes,
+            } if trial => {
+                *in_flight -= 1;
+                if failed {
+                    self.open(&mut state, "trial request failed");
+                    return;
+                }
+                *successes += 1;
+                if *successes >= self.config.half_open_requests {
+                    log::info!("Circuit breaker for backend {} closed", self.name);
+                    state.state = State::Closed;
+                    state.consecutive_failures = 0;
+                    state.window_start = Instant::now();
+                    state.window_requests = 0;
+                    state.window_failures = 0;
+                }
+            }
+            // Requests that started before the breaker opened or went
+            // half-open no longer say anything about the backend.
+            _ => {}
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let State::HalfOpen { in_flight, .. } = &mut state.state {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+    }
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, failed: bool) {
+        self.breaker.record(self.trial, failed);
+        self.trial = false;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial {
+            self.breaker.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{CircuitBreaker, CircuitBreakerConfig, State};
+    use std::time::Duration;
+
+    fn breaker(error_rate: Option<f64>) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            &CircuitBreakerConfig {
+                consecutive_failures: 3,
+                error_rate,
+                min_requests: 4,
+                window: 60.0,
+                open_time: 0.05,
+                half_open_requests: 1,
+            },
+        )
+    }
+
+    fn request(breaker: &CircuitBreaker, failed: bool) {
+        breaker.acquire().unwrap().record(failed);
+    }
+
+    fn is_open(breaker: &CircuitBreaker) -> bool {
+        matches!(breaker.state.lock().unwrap().state, State::Open { .. })
+    }
+
+    #[test]
+    fn consecutive_failures_open() {
+        let breaker = breaker(None);
+        request(&breaker, true);
+        request(&breaker, true);
+        request(&breaker, false);
+        request(&breaker, true);
+        request(&breaker, true);
+        assert!(!is_open(&breaker));
+        request(&breaker, true);
+        assert!(is_open(&breaker));
+        let retry_after = breaker.acquire().err().unwrap();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn error_rate_opens() {
+        let breaker = breaker(Some(0.5));
+        request(&breaker, false);
+        request(&breaker, true);
+        request(&breaker, false);
+        assert!(!is_open(&breaker));
+        request(&breaker, true);
+        assert!(is_open(&breaker));
+    }
+
+    #[test]
+    fn half_open_allows_limited_trials() {
+        let breaker = breaker(None);
+        for _ in 0..3 {
+            request(&breaker, true);
+        }
+        std::thread::sleep(Duration::from_millis(60));
+
+        let trial = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+        trial.record(false);
+        assert_eq!(breaker.state.lock().unwrap().state, State::Closed);
+        request(&breaker, true);
+        assert!(!is_open(&breaker));
+    }
+
+    #[test]
+    fn failed_trial_reopens() {
+        let breaker = breaker(None);
+        for _ in 0..3 {
+            request(&breaker, true);
+        }
+        std::thread::sleep(Duration::from_millis(60));
+        request(&breaker, true);
+        assert!(is_open(&breaker));
+    }
+
+    #[test]
+    fn dropped_trial_is_released() {
+        let breaker = breaker(None);
+        for _ in 0..3 {
+            request(&breaker, true);
+        }
+        std::thread::sleep(Duration::from_millis(60));
+        drop(breaker.acquire().unwrap());
+        assert!(breaker.acquire().is_ok());
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let config = CircuitBreakerConfig {
+            error_rate: Some(1.5),
+            ..breaker(None).config
+        };
+        assert!(config.validate().is_err());
+        assert!(breaker(Some(0.5)).config.validate().is_ok());
+    }
+}
@@ -0,0 +1,341 @@
+use super::balance::Upstream;
+use super::{send, TimeoutConfig};
+use crate::config::Config;
+use crate::SERVER_VIA;
+use hyper::header::VIA;
+use hyper::{Body, Request, Uri};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Active health checking of the upstreams of a backend.
+///
+/// Each upstream is probed with a `GET` of `path` below its URL. After
+/// `unhealthy_threshold` failed probes in a row it is taken out of rotation,
+/// and after `healthy_threshold` successful probes in a row it is put back.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Seconds between probes.
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    /// Seconds allowed for a probe, including the response body.
+    #[serde(default = "default_timeout")]
+    pub timeout: f64,
+    #[serde(default = "default_expected_status")]
+    pub expected_status: Vec<u16>,
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_interval() -> f64 {
+    10.0
+}
+
+fn default_timeout() -> f64 {
+    2.0
+}
+
+fn default_expected_status() -> Vec<u16> {
+    vec![200]
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+/// Passive health checking, which takes an upstream out of rotation for
+/// `ejection_time` seconds after `consecutive_errors` proxied requests to it
+/// fail in a row.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierConfig {
+    #[serde(default = "default_consecutive_errors")]
+    pub consecutive_errors: u32,
+    #[serde(default = "default_ejection_time")]
+    pub ejection_time: f64,
+}
+
+fn default_consecutive_errors() -> u32 {
+    5
+}
+
+fn default_ejection_time() -> f64 {
+    30.0
+}
+
+fn positive(name: &str, value: f64) -> Result<(), String> {
+    if !value.is_finite() || value <= 0.0 {
+        return Err(format!("{} must be a positive number", name));
+    }
+    Ok(())
+}
+
+impl HealthCheckConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        positive("health_check interval", self.interval)?;
+        positive("health_check timeout", self.timeout)?;
+        if !self.path.starts_with('/') {
+            return Err("health_check path must start with /".to_string());
+        }
+        if self.healthy_threshold == 0 || self.unhealthy_threshold == 0 {
+            return Err("health_check thresholds must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl OutlierConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        positive("outlier_detection ejection_time", self.ejection_time)?;
+        if self.consecutive_errors == 0 {
+            return Err("outlier_detection consecutive_errors must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The health of an upstream, shared by every request to it and by its
+/// health check.
+#[derive(Debug, Default)]
+pub struct Health {
+    /// Set by the active health check.
+    unhealthy: AtomicBool,
+    probe_streak: AtomicU32,
+    /// Proxied requests that have failed in a row.
+    consecutive_errors: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+/// A snapshot of the health of an upstream, as shown by the admin endpoint.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HealthStatus {
+    pub healthy: bool,
+    pub ejected: bool,
+    pub consecutive_errors: u32,
+}
+
+impl Health {
+    /// Returns false while the upstream has failed its health check or is
+    /// ejected.
+    pub fn in_rotation(&self) -> bool {
+        !self.unhealthy.load(Ordering::Relaxed) && !self.is_ejected()
+    }
+
+    fn is_ejected(&self) -> bool {
+        match *self.ejected_until.lock().unwrap() {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        HealthStatus {
+            healthy: !self.unhealthy.load(Ordering::Relaxed),
+            ejected: self.is_ejected(),
+            consecutive_errors: self.consecutive_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Records the outcome of a proxied request for outlier detection.
+    pub fn record(&self, url: &str, failed: bool, outlier: Option<&OutlierConfig>) {
+        if !failed {
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+            return;
+        }
+        let errors = self.consecutive_errors.fetch_add(1, Ordering::Relaxed) + 1;
+        let outlier = match outlier {
+            Some(outlier) if errors >= outlier.consecutive_errors => outlier,
+            _ => return,
+        };
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        if ejected_until.is_none_or(|until| Instant::now() >= until) {
+            log::warn!(
+                "Upstream {} ejected for {}s after {} consecutive errors",
+                url,
+                outlier.ejection_time,
+                errors
+            );
+            *ejected_until = Some(Instant::now() + Duration::from_secs_f64(outlier.ejection_time));
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the result of a probe, logging when the upstream changes
+    /// state.
+    fn record_probe(&self, url: &str, result: Result<(), String>, check: &HealthCheckConfig) {
+        let unhealthy = self.unhealthy.load(Ordering::Relaxed);
+        let (threshold, state_changes) = match (&result, unhealthy) {
+            (Ok(()), true) => (check.healthy_threshold, true),
+            (Err(_), false) => (check.unhealthy_threshold, true),
+            _ => (0, false),
+        };
+        if !state_changes {
+            self.probe_streak.store(0, Ordering::Relaxed);
+            return;
+        }
+        let streak = self.probe_streak.fetch_add(1, Ordering::Relaxed) + 1;
+        if streak < threshold {
+            return;
+        }
+        self.probe_streak.store(0, Ordering::Relaxed);
+        self.unhealthy.store(!unhealthy, Ordering::Relaxed);
+        match result {
+            Ok(()) => log::info!("Upstream {} is healthy", url),
+            Err(err) => log::warn!("Upstream {} is unhealthy: {}", url, err),
+        }
+    }
+}
+
+async fn probe(upstream: &Upstream, check: &HealthCheckConfig) -> Result<(), String> {
+    let uri: Uri = format!("{}{}", upstream.url.trim_end_matches('/'), check.path)
+        .parse()
+        .map_err(|e| format!("Invalid health check URL: {}", e))?;
+    let req = Request::get(uri)
+        .header(VIA, SERVER_VIA)
+        .body(Body::empty())
+        .unwrap();
+    let timeouts = TimeoutConfig {
+        total: Some(check.timeout),
+        ..TimeoutConfig::default()
+    };
+    let response = send(upstream.client(), req, &timeouts)
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    if check.expected_status.contains(&status.as_u16()) {
+        Ok(())
+    } else {
+        Err(format!("Unexpected status {}", status))
+    }
+}
+
+/// Starts probing the upstreams of every backend with a health check.
+pub fn spawn_health_checks(config: &Config) {
+    for (name, backend) in &config.backends {
+        let check = match &backend.health_check {
+            Some(check) => check,
+            None => continue,
+        };
+        log::debug!("Starting health checks for backend {}", name);
+        for upstream in &backend.upstreams {
+            let upstream = upstream.clone();
+            let check = check.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs_f64(check.interval));
+                loop {
+                    interval.tick().await;
+                    let result = probe(&upstream, &check).await;
+                    upstream
+                        .health()
+                        .record_probe(&upstream.url, result, &check);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Health, HealthCheckConfig, OutlierConfig};
+
+    fn check() -> HealthCheckConfig {
+        HealthCheckConfig {
+            path: "/healthz".to_string(),
+            interval: 1.0,
+            timeout: 1.0,
+            expected_status: vec![200],
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+
+    fn probes(health: &Health, results: &[bool]) -> Vec<bool> {
+        results
+            .iter()
+            .map(|&ok| {
+                let result = if ok { Ok(()) } else { Err("down".to_string()) };
+                health.record_probe("http://upstream", result, &check());
+                health.in_rotation()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn probe_thresholds() {
+        let health = Health::default();
+        assert_eq!(
+            probes(&health, &[false, false, true, false, false, false]),
+            vec![true, true, true, true, true, false]
+        );
+        assert_eq!(
+            probes(&health, &[true, false, true, true, true]),
+            vec![false, false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn consecutive_errors_eject() {
+        let outlier = OutlierConfig {
+            consecutive_errors: 2,
+            ejection_time: 60.0,
+        };
+        let health = Health::default();
+        health.record("http://upstream", true, Some(&outlier));
+        health.record("http://upstream", false, Some(&outlier));
+        health.record("http://upstream", true, Some(&outlier));
+        assert!(health.in_rotation());
+        health.record("http://upstream", true, Some(&outlier));
+        assert!(!health.in_rotation());
+        assert!(health.status().ejected);
+        assert!(health.status().healthy);
+    }
+
+    #[test]
+    fn errors_without_outlier_detection_do_not_eject() {
+        let health = Health::default();
+        for _ in 0..100 {
+            health.record("http://upstream", true, None);
+        }
+        assert!(health.in_rotation());
+        assert_eq!(health.status().consecutive_errors, 100);
+    }
+
+    #[test]
+    fn invalid_health_checks_are_rejected() {
+        assert!(check().validate().is_ok());
+        let invalid = HealthCheckConfig {
+            path: "healthz".to_string(),
+            ..check()
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = HealthCheckConfig {
+            unhealthy_threshold: 0,
+            ..check()
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = OutlierConfig {
+            consecutive_errors: 5,
+            ejection_time: -1.0,
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,332 @@
+use crate::config::HttpsClient;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response};
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+pub mod balance;
+pub mod breaker;
+pub mod health;
+pub mod retry;
+
+/// Timeouts for requests to a backend, in seconds.
+#[derive(Clone, Default, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Time allowed to establish a TCP connection.
+    pub connect: Option<f64>,
+    /// Time allowed between sending the request and receiving the response
+    /// headers.
+    pub response_header: Option<f64>,
+    /// Time allowed for the whole exchange, including the response body.
+    pub total: Option<f64>,
+}
+
+impl TimeoutConfig {
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect.map(Duration::from_secs_f64)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in &[
+            ("connect", self.connect),
+            ("response_header", self.response_header),
+            ("total", self.total),
+        ] {
+            if let Some(value) = value {
+                if !value.is_finite() || *value <= 0.0 {
+                    return Err(format!("{} timeout must be a positive number", name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The HTTP version spoken to the upstreams of a backend.
+#[derive(Clone, Copy, Default, Deserialize, Debug, PartialEq)]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 only, negotiated with ALPN for `https` upstreams and with prior
+    /// knowledge (h2c) for `http` upstreams.
+    Http2,
+    /// HTTP/2 if a `https` upstream offers it with ALPN, and HTTP/1.1
+    /// otherwise.
+    Auto,
+}
+
+impl UpstreamProtocol {
+    /// The protocols offered with ALPN when connecting to an upstream.
+    pub fn alpn_protocols(self) -> Vec<Vec<u8>> {
+        match self {
+            UpstreamProtocol::Http1 => vec![],
+            UpstreamProtocol::Http2 => vec![b"h2".to_vec()],
+            UpstreamProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        }
+    }
+}
+
+/// Why a request to a backend failed.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// The backend did not respond in time.
+    Timeout(&'static str),
+    /// A connection to the backend could not be established, including TLS
+    /// handshake failures.
+    Connect(hyper::Error),
+    /// The backend closed the connection or sent an invalid response.
+    Protocol(hyper::Error),
+    /// The request body could not be read from the client to be buffered.
+    RequestBody(hyper::Error),
+    /// The circuit breaker of the backend is open, and the client should
+    /// wait before retrying.
+    CircuitOpen(Duration),
+    /// The request could not be pointed at the upstream.
+    Target(String),
+}
+
+impl UpstreamError {
+    /// Returns how long the client should wait before retrying, if known.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            UpstreamError::CircuitOpen(wait) => Some(*wait),
+            _ => None,
+        }
+    }
+
+    fn from_hyper(err: hyper::Error) -> Self {
+        if is_timeout(&err) {
+            UpstreamError::Timeout("connect")
+        } else if err.is_connect() {
+            UpstreamError::Connect(err)
+        } else {
+            UpstreamError::Protocol(err)
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Timeout(phase) => write!(f, "Timed out waiting for {}", phase),
+            UpstreamError::Connect(err) => write!(f, "Connection failed: {}", err),
+            UpstreamError::Protocol(err) => write!(f, "Protocol error: {}", err),
+            UpstreamError::RequestBody(err) => write!(f, "Unable to read request body: {}", err),
+            UpstreamError::CircuitOpen(_) => write!(f, "Circuit breaker is open"),
+            UpstreamError::Target(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+fn is_timeout(err: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            if io_err.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Sends a request to a backend, applying the configured timeouts.
+///
+/// The total timeout also covers streaming the response body; if it expires
+/// mid-body the body is aborted with an error.
+pub async fn send(
+    client: &HttpsClient,
+    req: Request<Body>,
+    timeouts: &TimeoutConfig,
+) -> Result<Response<Body>, UpstreamError> {
+    let now = Instant::now();
+    let total = timeouts.total.map(|t| now + Duration::from_secs_f64(t));
+    let header = timeouts
+        .response_header
+        .map(|t| now + Duration::from_secs_f64(t));
+    let (header_deadline, phase) = match (header, total) {
+        (Some(h), Some(t)) if t < h => (Some(t), "the whole response"),
+        (Some(h), _) => (Some(h), "response headers"),
+        (None, Some(t)) => (Some(t), "the whole response"),
+        (None, None) => (None, ""),
+    };
+
+    let response = match header_deadline {
+        Some(deadline) => match timeout_at(deadline, client.request(req)).await {
+            Ok(result) => result,
+            Err(_) => return Err(UpstreamError::Timeout(phase)),
+        },
+        None => client.request(req).await,
+    };
+    let response = response.map_err(UpstreamError::from_hyper)?;
+
+    Ok(match total {
+        Some(deadline) => response.map(|body| body_with_deadline(body, deadline)),
+        None => response,
+    })
+}
+
+/// Streams a response body to the client until `deadline`, aborting it if
+/// the backend has not finished by then. Trailers are passed on, as gRPC
+/// sends its status in them.
+fn body_with_deadline(mut body: Body, deadline: Instant) -> Body {
+    let (mut sender, streamed) = Body::channel();
+    tokio::spawn(async move {
+        let forward = async {
+            while let Some(chunk) = body.data().await {
+                if sender.send_data(chunk?).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if let Some(trailers) = body.trailers().await? {
+                let _ = sender.send_trailers(trailers).await;
+            }
+            Ok::<_, hyper::Error>(())
+        };
+        match timeout_at(deadline, forward).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                log::debug!(
+                    "Error streaming the response body from the backend: {}",
+                    err
+                );
+                sender.abort();
+            }
+            Err(_) => {
+                log::warn!("Timed out streaming the response body from the backend");
+                sender.abort();
+            }
+        }
+    });
+    streamed
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{send, TimeoutConfig, UpstreamError};
+    use crate::config::HttpsClient;
+    use hyper::{Body, Client, Request};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn client(timeouts: &TimeoutConfig) -> HttpsClient {
+        let mut http = hyper::client::HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(timeouts.connect());
+        let tls = rustls::ClientConfig::new();
+        Client::builder().build(hyper_rustls::HttpsConnector::from((http, tls)))
+    }
+
+    fn request(addr: SocketAddr) -> Request<Body> {
+        Request::get(format!("http://{}/", addr))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Starts a server that answers each connection with `response` after
+    /// `delay`.
+    async fn server(response: &'static str, delay: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    tokio::time::sleep(delay).await;
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn successful_response() {
+        let timeouts = TimeoutConfig {
+            response_header: Some(1.0),
+            total: Some(1.0),
+            ..TimeoutConfig::default()
+        };
+        let addr = server(
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok",
+            Duration::ZERO,
+        )
+        .await;
+        let response = send(&client(&timeouts), request(addr), &timeouts)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+
+    #[tokio::test]
+    async fn response_header_timeout() {
+        let timeouts = TimeoutConfig {
+            response_header: Some(0.05),
+            ..TimeoutConfig::default()
+        };
+        let addr = server("HTTP/1.1 200 OK\r\n\r\n", Duration::from_secs(1)).await;
+        let result = send(&client(&timeouts), request(addr), &timeouts).await;
+        assert!(matches!(
+            result,
+            Err(UpstreamError::Timeout("response headers"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn total_timeout_aborts_body() {
+        let timeouts = TimeoutConfig {
+            total: Some(0.1),
+            ..TimeoutConfig::default()
+        };
+        let addr = server(
+            "HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nok",
+            Duration::ZERO,
+        )
+        .await;
+        let response = send(&client(&timeouts), request(addr), &timeouts)
+            .await
+            .unwrap();
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connection_refused_is_connect_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let timeouts = TimeoutConfig::default();
+        let result = send(&client(&timeouts), request(addr), &timeouts).await;
+        assert!(matches!(result, Err(UpstreamError::Connect(_))));
+    }
+
+    #[tokio::test]
+    async fn invalid_response_is_protocol_error() {
+        let timeouts = TimeoutConfig::default();
+        let addr = server("NOT HTTP\r\n\r\n", Duration::ZERO).await;
+        let result = send(&client(&timeouts), request(addr), &timeouts).await;
+        assert!(matches!(result, Err(UpstreamError::Protocol(_))));
+    }
+
+    #[test]
+    fn invalid_timeouts_are_rejected() {
+        let timeouts = TimeoutConfig {
+            connect: Some(0.0),
+            ..TimeoutConfig::default()
+        };
+        assert!(timeouts.validate().is_err());
+        assert!(TimeoutConfig::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,609 @@
+use super::balance::Upstream;
+use super::UpstreamError;
+use crate::config::Backend;
+use crate::proxy::UpstreamPath;
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::request::Parts;
+use hyper::http::Extensions;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use rand::Rng;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The kinds of failure that a request can be retried after.
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum FailureClass {
+    /// The connection could not be established, so the backend never saw the
+    /// request.
+    Connect,
+    /// The backend did not respond in time.
+    Timeout,
+    /// The backend closed the connection or sent an invalid response, such as
+    /// when a pooled keep-alive connection has been dropped.
+    Protocol,
+    /// The backend responded with 502, 503 or 504.
+    Unavailable,
+}
+
+impl FailureClass {
+    fn of(result: &Result<Response<Body>, UpstreamError>) -> Option<Self> {
+        match result {
+            Ok(response) => match response.status() {
+                StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT => Some(FailureClass::Unavailable),
+                _ => None,
+            },
+            Err(UpstreamError::Connect(_)) => Some(FailureClass::Connect),
+            Err(UpstreamError::Timeout(_)) => Some(FailureClass::Timeout),
+            Err(UpstreamError::Protocol(_)) => Some(FailureClass::Protocol),
+            Err(UpstreamError::RequestBody(_))
+            | Err(UpstreamError::CircuitOpen(_))
+            | Err(UpstreamError::Target(_)) => None,
+        }
+    }
+}
+
+/// Retry policy for requests to a backend.
+///
+/// Only requests whose body can be buffered are retried. Requests with
+/// idempotent methods are retried after any of the configured failures,
+/// other requests only after connect failures, as the backend cannot have
+/// acted on them.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts per request, including the first. 1 disables retries.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<FailureClass>,
+    /// Seconds to wait before the first retry, doubling for each retry after.
+    #[serde(default = "default_backoff_base")]
+    pub backoff_base: f64,
+    /// Upper bound in seconds on the wait between attempts.
+    #[serde(default = "default_backoff_max")]
+    pub backoff_max: f64,
+    /// Retries earned by each request, e.g. 0.2 allows one retry per five
+    /// requests once the budget's reserve has been spent.
+    #[serde(default = "default_budget_ratio")]
+    pub budget_ratio: f64,
+    /// Retries that can be made in a burst, and the reserve the budget
+    /// starts with.
+    #[serde(default = "default_budget_capacity")]
+    pub budget_capacity: u32,
+    /// Largest request body, in bytes, that is buffered so that it can be
+    /// sent again.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    1
+}
+
+fn default_retry_on() -> Vec<FailureClass> {
+    vec![FailureClass::Connect, FailureClass::Protocol]
+}
+
+fn default_backoff_base() -> f64 {
+    0.05
+}
+
+fn default_backoff_max() -> f64 {
+    1.0
+}
+
+fn default_budget_ratio() -> f64 {
+    0.2
+}
+
+fn default_budget_capacity() -> u32 {
+    10
+}
+
+fn default_max_body_bytes() -> u64 {
+    64 * 1024
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            retry_on: default_retry_on(),
+            backoff_base: default_backoff_base(),
+            backoff_max: default_backoff_max(),
+            budget_ratio: default_budget_ratio(),
+            budget_capacity: default_budget_capacity(),
+            max_body_bytes: default_max_body_bytes(),
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("retry max_attempts must be at least 1".to_string());
+        }
+        if !self.backoff_base.is_finite() || self.backoff_base < 0.0 {
+            return Err("retry backoff_base must be a non-negative number".to_string());
+        }
+        if !self.backoff_max.is_finite() || self.backoff_max < self.backoff_base {
+            return Err("retry backoff_max must be at least backoff_base".to_string());
+        }
+        if !self.budget_ratio.is_finite() || self.budget_ratio < 0.0 {
+            return Err("retry budget_ratio must be a non-negative number".to_string());
+        }
+        Ok(())
+    }
+
+    fn retries(&self, failure: FailureClass, idempotent: bool) -> bool {
+        self.retry_on.contains(&failure) && (idempotent || failure == FailureClass::Connect)
+    }
+
+    /// The longest wait before the given retry, starting from 1.
+    fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31) as i32;
+        let delay = self.backoff_base * 2f64.powi(exponent);
+        Duration::from_secs_f64(delay.min(self.backoff_max))
+    }
+
+    /// A random wait between zero and the backoff, so that clients which
+    /// failed together do not retry together.
+    fn jittered_backoff(&self, retry: u32) -> Duration {
+        self.backoff(retry).mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Limits retries to a share of the requests to a backend, so that retries
+/// cannot multiply the load on a backend that is already failing.
+///
+/// Clones share the same balance.
+#[derive(Clone, Debug, Default)]
+pub struct RetryBudget(Arc<Mutex<BudgetState>>);
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    balance: f64,
+    ratio: f64,
+    capacity: f64,
+}
+
+impl RetryBudget {
+    pub fn new(config: &RetryConfig) -> Self {
+        let capacity = f64::from(config.budget_capacity);
+        RetryBudget(Arc::new(Mutex::new(BudgetState {
+            balance: capacity,
+            ratio: config.budget_ratio,
+            capacity,
+        })))
+    }
+
+    fn deposit(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.balance = (state.balance + state.ratio).min(state.capacity);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.balance >= 1.0 {
+            state.balance -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Copies the extensions of a request that every attempt needs. Extensions
+/// cannot be cloned in general, so each one is copied by type.
+fn copy_extensions(extensions: &Extensions) -> Extensions {
+    let mut copy = Extensions::new();
+    if let Some(path) = extensions.get::<UpstreamPath>() {
+        copy.insert(path.clone());
+    }
+    copy
+}
+
+fn rebuild(parts: &Parts, body: Bytes, extensions: Extensions) -> Request<Body> {
+    let mut req = Request::new(Body::from(body));
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    *req.extensions_mut() = extensions;
+    req
+}
+
+fn describe(result: &Result<Response<Body>, UpstreamError>) -> String {
+    match result {
+        Ok(response) => format!("status {}", response.status()),
+        Err(err) => err.to_string(),
+    }
+}
+
+async fn send_once(
+    backend: &Backend,
+    upstream: &Upstream,
+    req: Request<Body>,
+) -> Result<Response<Body>, UpstreamError> {
+    let result = upstream.send(req, &backend.timeouts).await;
+    let failed = FailureClass::of(&result).is_some();
+    upstream
+        .health()
+        .record(&upstream.url, failed, backend.outlier_detection.as_ref());
+    result
+}
+
+/// Sends a request to an upstream of a backend.
+///
+/// When the request body can be buffered, an attempt that fails to connect
+/// moves straight on to an upstream not yet tried for the request, and
+/// other failed attempts are retried according to the backend's retry
+/// policy. If the backend has a circuit breaker, the request fails fast
+/// while it is open, and its final outcome is recorded by the breaker.
+pub async fn send_with_retries(
+    backend: &Backend,
+    req: Request<Body>,
+) -> Result<Response<Body>, UpstreamError> {
+    let breaker = match backend.breaker() {
+        Some(breaker) => breaker,
+        None => return send_attempts(backend, req).await,
+    };
+    let permit = breaker.acquire().map_err(UpstreamError::CircuitOpen)?;
+    let result = send_attempts(backend, req).await;
+    permit.record(FailureClass::of(&result).is_some());
+    result
+}
+
+async fn send_attempts(
+    backend: &Backend,
+    req: Request<Body>,
+) -> Result<Response<Body>, UpstreamError> {
+    let retry = &backend.retry;
+    let upstreams = &backend.upstreams;
+    if upstreams.is_empty() {
+        return Err(UpstreamError::Target(
+            "Backend has no upstreams".to_string(),
+        ));
+    }
+    if retry.max_attempts <= 1 && upstreams.len() == 1 {
+        return send_once(backend, &upstreams[0], req).await;
+    }
+
+    let (mut parts, body) = req.into_parts();
+    match body.size_hint().upper() {
+        Some(size) if size <= retry.max_body_bytes => {}
+        _ => {
+            log::debug!("Request body is too large to retry");
+            let upstream = &upstreams[backend.pick_upstream(&[])];
+            return send_once(backend, upstream, Request::from_parts(parts, body)).await;
+        }
+    }
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(UpstreamError::RequestBody)?;
+    let idempotent = is_idempotent(&parts.method);
+    let budget = backend.retry_budget();
+    budget.deposit();
+
+    // The first attempt takes all the extensions of the request, and later
+    // attempts get copies of those they need
+    let copied = copy_extensions(&parts.extensions);
+    let mut extensions = Some(std::mem::take(&mut parts.extensions));
+    let mut tried = Vec::new();
+    let mut attempt = 1;
+    loop {
+        let index = backend.pick_upstream(&tried);
+        tried.push(index);
+        let upstream = &upstreams[index];
+        let extensions = extensions
+            .take()
+            .unwrap_or_else(|| copy_extensions(&copied));
+        let result = upstream
+            .send(rebuild(&parts, body.clone(), extensions), &backend.timeouts)
+            .await;
+        let failure = FailureClass::of(&result);
+        upstream.health().record(
+            &upstream.url,
+            failure.is_some(),
+            backend.outlier_detection.as_ref(),
+        );
+        let failure = match failure {
+            Some(failure) => failure,
+            None => return result,
+        };
+        if failure == FailureClass::Connect && tried.len() < upstreams.len() {
+            log::warn!(
+                "Upstream {} failed: {}, trying another upstream",
+                upstream.url,
+                describe(&result)
+            );
+            continue;
+        }
+        if attempt >= retry.max_attempts || !retry.retries(failure, idempotent) {
+            return result;
+        }
+        if !budget.withdraw() {
+            log::warn!("Retry budget exhausted, not retrying {}", parts.uri);
+            return result;
+        }
+
+        let delay = retry.jittered_backoff(attempt);
+        log::warn!(
+            "Retrying {} {} in {:?} after {} from {} (attempt {} of {})",
+            parts.method,
+            parts.uri,
+            delay,
+            describe(&result),
+            upstream.url,
+            attempt + 1,
+            retry.max_attempts
+        );
+        drop(result);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{send_with_retries, FailureClass, RetryBudget, RetryConfig};
+    use crate::config::tests::config;
+    use crate::config::Backend;
+    use crate::proxy::UpstreamPath;
+    use crate::upstream::UpstreamError;
+    use hyper::{Body, Method, Request};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn retry_config(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            backoff_base: 0.001,
+            backoff_max: 0.01,
+            ..RetryConfig::default()
+        }
+    }
+
+    /// Builds a backend with the given upstreams, retry settings and any
+    /// other settings.
+    fn backend(upstreams: &[SocketAddr], retry: &str, settings: &str) -> Backend {
+        let upstreams: Vec<String> = upstreams
+            .iter()
+            .map(|addr| format!("{{ url = \"http://{}\" }}", addr))
+            .collect();
+        let config = config(&format!(
+            r#"
+            [backends.test]
+            scope = "test:*"
+            upstreams = [{}]
+            retry = {{ backoff_base = 0.001, backoff_max = 0.01, {} }}
+            {}
+            "#,
+            upstreams.join(", "),
+            retry,
+            settings
+        ))
+        .unwrap();
+        config.backends["test"].clone()
+    }
+
+    fn request(method: Method, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/test/")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    /// Starts a server that drops the first `failures` connections without
+    /// responding and answers the rest with 200. Returns the address and the
+    /// number of connections accepted.
+    async fn flaky_server(failures: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let accepted = count.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let n = accepted.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                if n >= failures {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                        .await;
+                }
+            }
+        });
+        (addr, count)
+    }
+
+    /// Starts a server that answers every request with 200 and records the
+    /// request line of each.
+    async fn recording_server() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let recorded = lines.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let line = request.lines().next().unwrap_or("").to_string();
+                recorded.lock().unwrap().push(line);
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                    .await;
+            }
+        });
+        (addr, lines)
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let retry = RetryConfig {
+            backoff_base: 0.1,
+            backoff_max: 0.5,
+            ..RetryConfig::default()
+        };
+        assert_eq!(retry.backoff(1), Duration::from_millis(100));
+        assert_eq!(retry.backoff(2), Duration::from_millis(200));
+        assert_eq!(retry.backoff(3), Duration::from_millis(400));
+        assert_eq!(retry.backoff(4), Duration::from_millis(500));
+        assert_eq!(retry.backoff(100), Duration::from_millis(500));
+        for _ in 0..100 {
+            assert!(retry.jittered_backoff(2) <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn non_idempotent_requests_only_retry_connect_failures() {
+        let retry = RetryConfig::default();
+        assert!(retry.retries(FailureClass::Connect, true));
+        assert!(retry.retries(FailureClass::Protocol, true));
+        assert!(retry.retries(FailureClass::Connect, false));
+        assert!(!retry.retries(FailureClass::Protocol, false));
+        assert!(!retry.retries(FailureClass::Timeout, true));
+    }
+
+    #[test]
+    fn budget_limits_retries() {
+        let budget = RetryBudget::new(&RetryConfig {
+            budget_ratio: 0.5,
+            budget_capacity: 2,
+            ..RetryConfig::default()
+        });
+        assert!(budget.withdraw());
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(budget.withdraw());
+        for _ in 0..10 {
+            budget.deposit();
+        }
+        assert!(budget.withdraw());
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn invalid_retry_configs_are_rejected() {
+        assert!(RetryConfig::default().validate().is_ok());
+        assert!(retry_config(0).validate().is_err());
+        let retry = RetryConfig {
+            backoff_base: 2.0,
+            backoff_max: 1.0,
+            ..RetryConfig::default()
+        };
+        assert!(retry.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_is_retried() {
+        let (addr, count) = flaky_server(1).await;
+        let backend = backend(&[addr], "max_attempts = 3", "");
+        let response = send_with_retries(&backend, request(Method::PUT, "body"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn attempts_are_limited() {
+        let (addr, count) = flaky_server(usize::MAX).await;
+        let backend = backend(&[addr], "max_attempts = 3", "");
+        let result = send_with_retries(&backend, request(Method::GET, "")).await;
+        assert!(matches!(result, Err(UpstreamError::Protocol(_))));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_request_is_not_resent() {
+        let (addr, count) = flaky_server(1).await;
+        let backend = backend(&[addr], "max_attempts = 3", "");
+        let result = send_with_retries(&backend, request(Method::POST, "body")).await;
+        assert!(matches!(result, Err(UpstreamError::Protocol(_))));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_not_retried() {
+        let (addr, count) = flaky_server(1).await;
+        let backend = backend(&[addr], "max_attempts = 3, max_body_bytes = 2", "");
+        let result = send_with_retries(&backend, request(Method::PUT, "body")).await;
+        assert!(result.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn open_circuit_fails_fast() {
+        let (addr, count) = flaky_server(usize::MAX).await;
+        let backend = backend(
+            &[addr],
+            "max_attempts = 1",
+            "circuit_breaker = { consecutive_failures = 2 }",
+        );
+        for _ in 0..2 {
+            let result = send_with_retries(&backend, request(Method::GET, "")).await;
+            assert!(matches!(result, Err(UpstreamError::Protocol(_))));
+        }
+        let result = send_with_retries(&backend, request(Method::GET, "")).await;
+        assert!(matches!(result, Err(UpstreamError::CircuitOpen(_))));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstream_is_skipped() {
+        let (addr, count) = flaky_server(0).await;
+        let backend = backend(&[closed_port().await, addr], "max_attempts = 1", "");
+        for _ in 0..4 {
+            let response = send_with_retries(&backend, request(Method::POST, "body"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn every_attempt_is_sent_to_the_upstream_path() {
+        let (addr, lines) = recording_server().await;
+        let backend = backend(&[closed_port().await, addr], "max_attempts = 2", "");
+        for _ in 0..2 {
+            let mut req = request(Method::GET, "");
+            *req.uri_mut() = "/cats/x/../../../etc/passwd".parse().unwrap();
+            req.extensions_mut()
+                .insert(UpstreamPath::new("/a".to_string(), Some("b=1")));
+            let response = send_with_retries(&backend, req).await.unwrap();
+            assert_eq!(response.status(), 200);
+        }
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec!["GET /a?b=1 HTTP/1.1", "GET /a?b=1 HTTP/1.1"]
+        );
+    }
+}